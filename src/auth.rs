@@ -0,0 +1,216 @@
+//! Bearer token checks for incoming WHIP requests, either one of a set of
+//! static tokens or a JWT signed with HS256 or RS256
+
+use anyhow::{anyhow, Context, Result};
+use axum::http::{
+    header::{AUTHORIZATION, HOST},
+    HeaderMap,
+};
+use hmac::{Hmac, Mac};
+use jwt::{algorithm::openssl::PKeyWithDigest, AlgorithmType, Header, Token, VerifyWithKey};
+use openssl::{
+    hash::MessageDigest,
+    memcmp,
+    pkey::{PKey, Public},
+};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use url::Url;
+
+#[derive(Debug, Deserialize)]
+pub struct WhipClaims {
+    pub whip_url: String,
+    pub jti: String,
+    /// Seconds since the Unix epoch after which the token is no longer accepted
+    pub exp: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// No bearer token was sent
+    Missing,
+    /// The token is neither a known static token nor a validly signed JWT
+    Invalid,
+    /// The JWT is past its exp
+    Expired,
+    /// The token is valid but not for this request
+    Forbidden(&'static str),
+}
+
+impl AuthError {
+    pub fn status(&self) -> u16 {
+        match self {
+            AuthError::Missing | AuthError::Invalid | AuthError::Expired => 401,
+            AuthError::Forbidden(_) => 403,
+        }
+    }
+}
+
+pub struct Auth {
+    tokens: Vec<String>,
+    hs256: Option<Hmac<Sha256>>,
+    rs256: Option<PKeyWithDigest<Public>>,
+    // Single use JWT ids that have been redeemed already, with their exp
+    used_jtis: Mutex<HashMap<String, Option<u64>>>,
+}
+
+/// A request whose token checked out. A JWT's id is only used up once the
+/// grant is redeemed, so a request that fails later on doesn't burn the token
+#[derive(Debug)]
+pub struct Grant {
+    jti: Option<(String, Option<u64>)>,
+}
+
+impl Auth {
+    /// With no tokens or keys configured every request is let through
+    pub fn new(
+        tokens: Vec<String>,
+        jwt_secret: Option<&str>,
+        jwt_public_key: Option<&str>,
+    ) -> Result<Self> {
+        let hs256 = jwt_secret
+            .map(|secret| Hmac::new_from_slice(secret.as_bytes()))
+            .transpose()
+            .map_err(|_| anyhow!("Invalid JWT secret"))?;
+
+        let rs256 = jwt_public_key
+            .map(|path| -> Result<_> {
+                let pem = std::fs::read(path)
+                    .with_context(|| format!("Failed to read JWT public key {}", path))?;
+                let key = PKey::public_key_from_pem(&pem)
+                    .with_context(|| format!("Failed to parse JWT public key {}", path))?;
+                Ok(PKeyWithDigest {
+                    digest: MessageDigest::sha256(),
+                    key,
+                })
+            })
+            .transpose()?;
+
+        Ok(Self {
+            tokens,
+            hs256,
+            rs256,
+            used_jtis: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty() || self.hs256.is_some() || self.rs256.is_some()
+    }
+
+    /// Check the bearer token of a request made to `path`
+    pub fn check(&self, headers: &HeaderMap, path: &str) -> Result<Grant, AuthError> {
+        let mut grant = Grant { jti: None };
+        if !self.is_enabled() {
+            return Ok(grant);
+        }
+
+        let token = bearer_token(headers).ok_or(AuthError::Missing)?;
+        if self.tokens.iter().any(|known| tokens_match(known, token)) {
+            return Ok(grant);
+        }
+
+        let claims = self.verify_jwt(token)?;
+        if claims.exp.is_some_and(|exp| exp <= unix_time()) {
+            return Err(AuthError::Expired);
+        }
+        if !url_matches(&claims.whip_url, headers, path) {
+            return Err(AuthError::Forbidden("whip_url does not match"));
+        }
+        if self.used_jtis.lock().unwrap().contains_key(&claims.jti) {
+            return Err(AuthError::Forbidden("jti has already been used"));
+        }
+
+        grant.jti = Some((claims.jti, claims.exp));
+        Ok(grant)
+    }
+
+    /// Use up the JWT id of a grant once the request it was for has succeeded
+    pub fn redeem(&self, grant: Grant) -> Result<(), AuthError> {
+        let Some((jti, exp)) = grant.jti else {
+            return Ok(());
+        };
+
+        let mut used_jtis = self.used_jtis.lock().unwrap();
+        // Expired tokens are turned away anyway, their ids needn't be kept
+        let now = unix_time();
+        used_jtis.retain(|_, exp| exp.is_none_or(|exp| exp > now));
+        if used_jtis.insert(jti, exp).is_some() {
+            return Err(AuthError::Forbidden("jti has already been used"));
+        }
+
+        Ok(())
+    }
+
+    fn verify_jwt(&self, token: &str) -> Result<WhipClaims, AuthError> {
+        let token: Token<Header, WhipClaims, _> =
+            Token::parse_unverified(token).map_err(|_| AuthError::Invalid)?;
+
+        let verified = match token.header().algorithm {
+            AlgorithmType::Hs256 => match &self.hs256 {
+                Some(key) => token.verify_with_key(key),
+                None => return Err(AuthError::Invalid),
+            },
+            AlgorithmType::Rs256 => match &self.rs256 {
+                Some(key) => token.verify_with_key(key),
+                None => return Err(AuthError::Invalid),
+            },
+            _ => return Err(AuthError::Invalid),
+        };
+
+        let (_, claims) = verified.map_err(|_| AuthError::Invalid)?.into();
+        Ok(claims)
+    }
+}
+
+/// The bearer token a request was made with
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+/// Compare tokens in constant time, so timing doesn't give away how much of
+/// a guess was right
+pub fn tokens_match(known: &str, sent: &str) -> bool {
+    known.len() == sent.len() && memcmp::eq(known.as_bytes(), sent.as_bytes())
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Whether a token's whip_url is the endpoint it was sent to. The host is
+/// only compared when the request names one, a missing port is the default
+/// one of the whip_url's scheme
+fn url_matches(whip_url: &str, headers: &HeaderMap, path: &str) -> bool {
+    let Ok(whip_url) = Url::parse(whip_url) else {
+        return false;
+    };
+
+    if whip_url.path() != path {
+        return false;
+    }
+
+    let Some(host) = headers.get(HOST).and_then(|value| value.to_str().ok()) else {
+        return true;
+    };
+
+    let Ok(request_url) = Url::parse(&format!("{}://{}", whip_url.scheme(), host)) else {
+        return false;
+    };
+
+    whip_url.host_str().is_some()
+        && whip_url.host_str() == request_url.host_str()
+        && whip_url.port_or_known_default() == request_url.port_or_known_default()
+}
@@ -1,34 +1,51 @@
+use crate::ice::{self, IceServer, TurnAllocation};
+use crate::tcp::{self, TcpTransport};
 use bytes::Bytes;
 use local_ip_address::list_afinet_netifas;
-use reqwest::header::{HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE, USER_AGENT};
-use serde::Deserialize;
+use reqwest::header::{
+    HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE, ETAG, IF_MATCH, LINK, LOCATION, USER_AGENT,
+};
 use std::{
     error::Error,
+    future::poll_fn,
     io::ErrorKind,
-    net::{IpAddr, SocketAddr, SocketAddrV4},
+    net::{IpAddr, SocketAddr},
     str::FromStr,
+    task::Poll,
     time::{Duration, Instant},
 };
 use str0m::{
+    bwe::{Bitrate, BweKind},
     change::{SdpAnswer, SdpOffer},
     format::Codec,
-    media::{Direction as RtcDirection, MediaData, MediaKind, MediaTime, Mid},
+    media::{
+        Direction as RtcDirection, KeyframeRequestKind, MediaData, MediaKind, MediaTime, Mid, Rid,
+    },
     net::{Protocol, Receive},
     Candidate, Event, IceConnectionState, Input, Output, Rtc,
 };
-use tokio::net::UdpSocket;
+use tokio::{io::ReadBuf, net::UdpSocket};
 use tracing::{debug, error, info, trace, warn};
 
-#[derive(Debug, Deserialize)]
-pub struct WhipClaims {
-    pub whip_url: String,
-    pub jti: String,
-}
+// Packets of video held back waiting for a gap to be filled by a retransmission
+const VIDEO_REORDERING_SIZE: usize = 30;
+
+// How long a PLI is given to be answered before asking again
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+// ICE restarts in a row that may fail to bring a dropped connection back, and
+// how long to wait before each one after the first
+const MAX_ICE_RESTARTS: u32 = 3;
+const ICE_RESTART_DELAY: Duration = Duration::from_secs(2);
 
 #[derive(Debug)]
 pub enum WebrtcEvent {
     Continue,
     Media(MediaData),
+    /// The peer asked for a keyframe through PLI or FIR
+    KeyframeRequest,
+    /// How many bits per second the path to the peer is estimated to carry
+    BitrateEstimate(u64),
     Disconnected,
 }
 
@@ -44,72 +61,223 @@ pub enum WebrtcError {
 
 pub struct Client {
     rtc: Rtc,
-    socket: UdpSocket,
-    local_socket_addr: SocketAddr,
+    // One socket per interface address
+    sockets: Vec<HostSocket>,
+    tcp: TcpTransport,
     buf: [u8; 1500],
     video_mid: Option<Mid>,
-    _audio_mid: Option<Mid>,
+    audio_mid: Option<Mid>,
+    // Session resource handed back by the WHIP/WHEP server, deleted on close
+    resource_url: Option<reqwest::Url>,
+    token: Option<String>,
+    etag: Option<String>,
+    // Last full SDP on each side, ICE fragments from PATCH are merged into these
+    local_sdp: String,
+    remote_sdp: String,
+    turn: Option<TurnAllocation>,
+    last_keyframe_request: Option<Instant>,
+    // ICE restarts since the connection was last up
+    ice_restarts: u32,
+    // Simulcast layers the server takes, full size first, and whether it took
+    // simulcast at all
+    rids: Vec<String>,
+    simulcast: bool,
+}
+
+/// A UDP socket bound to a single interface address, which is its host candidate
+struct HostSocket {
+    socket: UdpSocket,
+    addr: SocketAddr,
+}
+
+/// The ICE parts of an SDP, as carried by `application/trickle-ice-sdpfrag`
+#[derive(Debug, Default)]
+pub struct IceFragment {
+    pub ufrag: Option<String>,
+    pub pwd: Option<String>,
+    /// `candidate:` attribute values
+    pub candidates: Vec<String>,
+}
+
+impl IceFragment {
+    /// Pull the ICE credentials and candidates out of a full SDP or a fragment
+    pub fn parse(sdp: &str) -> Self {
+        let mut fragment = Self::default();
+        for line in sdp.lines() {
+            if let Some(ufrag) = line.strip_prefix("a=ice-ufrag:") {
+                fragment.ufrag.get_or_insert_with(|| ufrag.to_string());
+            } else if let Some(pwd) = line.strip_prefix("a=ice-pwd:") {
+                fragment.pwd.get_or_insert_with(|| pwd.to_string());
+            } else if let Some(candidate) = line.strip_prefix("a=") {
+                if candidate.starts_with("candidate:") {
+                    fragment.candidates.push(candidate.to_string());
+                }
+            }
+        }
+
+        fragment
+    }
+
+    /// Render as a trickle-ice-sdpfrag for the first media section of `sdp`
+    pub fn to_sdpfrag(&self, sdp: &str) -> String {
+        let mut lines = Vec::new();
+        if let Some(ufrag) = &self.ufrag {
+            lines.push(format!("a=ice-ufrag:{}", ufrag));
+        }
+        if let Some(pwd) = &self.pwd {
+            lines.push(format!("a=ice-pwd:{}", pwd));
+        }
+
+        // Candidates are scoped to a media section, BUNDLE means the first
+        // one stands in for all of them
+        let mut media = sdp.lines().skip_while(|line| !line.starts_with("m="));
+        if let Some(m_line) = media.next() {
+            lines.push(m_line.to_string());
+            if let Some(mid) = media
+                .take_while(|line| !line.starts_with("m="))
+                .find(|line| line.starts_with("a=mid:"))
+            {
+                lines.push(mid.to_string());
+            }
+        }
+
+        for candidate in &self.candidates {
+            lines.push(format!("a={}", candidate));
+        }
+
+        lines.join("\r\n") + "\r\n"
+    }
+
+    /// Replace the ICE credentials and candidates of a full SDP with these
+    pub fn apply_to(&self, sdp: &str) -> String {
+        let mut lines = Vec::new();
+        for line in sdp.lines() {
+            if line.starts_with("a=candidate:") || line == "a=end-of-candidates" {
+                continue;
+            }
+
+            match (line.strip_prefix("a=ice-ufrag:"), &self.ufrag) {
+                (Some(_), Some(ufrag)) => lines.push(format!("a=ice-ufrag:{}", ufrag)),
+                _ => match (line.strip_prefix("a=ice-pwd:"), &self.pwd) {
+                    (Some(_), Some(pwd)) => {
+                        lines.push(format!("a=ice-pwd:{}", pwd));
+                        for candidate in &self.candidates {
+                            lines.push(format!("a={}", candidate));
+                        }
+                    }
+                    _ => lines.push(line.to_string()),
+                },
+            }
+        }
+
+        lines.join("\r\n") + "\r\n"
+    }
 }
 
 impl Client {
     pub async fn new() -> Result<Self, WebrtcError> {
-        let socket = UdpSocket::bind("0.0.0.0:0".parse::<SocketAddrV4>().unwrap())
-            .await
-            .expect("Should bind udp socket");
+        Self::create(None).await
+    }
 
-        let mut rtc = Rtc::builder()
+    /// A client that estimates the bandwidth towards the peer from transport
+    /// wide congestion control feedback, starting at `start_bitrate`
+    pub async fn with_bwe(start_bitrate: u64) -> Result<Self, WebrtcError> {
+        Self::create(Some(Bitrate::bps(start_bitrate))).await
+    }
+
+    async fn create(bwe: Option<Bitrate>) -> Result<Self, WebrtcError> {
+        let mut builder = Rtc::builder();
+        if bwe.is_some() {
+            builder = builder.enable_bwe(bwe);
+        }
+        let mut rtc = builder
             .clear_codecs()
             .enable_h264(true)
+            .enable_opus(true)
             .set_stats_interval(Some(Duration::from_secs(2)))
-            .set_reordering_size_video(1)
+            // Hold video back long enough for NACKed packets to be resent
+            // instead of handing the decoder a frame with a hole in it
+            .set_reordering_size_video(VIDEO_REORDERING_SIZE)
             .set_reordering_size_audio(1)
             .build();
 
-        info!("local socket address: {:?}", socket.local_addr());
-
-        // Discover host candidates
-        let mut local_socket_addr = None;
-        if let Ok(network_interfaces) = list_afinet_netifas() {
-            for (name, ip) in network_interfaces {
-                info!("iface: {} / {:?}", name, ip);
-                match ip {
-                    IpAddr::V4(ip4) => {
-                        if !ip4.is_loopback() && !ip4.is_link_local() {
-                            let socket_addr =
-                                SocketAddr::new(ip, socket.local_addr().unwrap().port());
-                            local_socket_addr = Some(socket_addr.clone());
-                            rtc.add_local_candidate(
-                                Candidate::host(socket_addr, str0m::net::Protocol::Udp)
-                                    .expect("Failed to create local candidate"),
-                            );
+        let Ok(network_interfaces) = list_afinet_netifas() else {
+            return Err(WebrtcError::NoCandidates);
+        };
+        // Discover host candidates. Binding each interface address on its own
+        // means a datagram's destination is known from the socket it arrives on
+        let mut sockets = Vec::new();
+        let mut tcp = TcpTransport::new();
+        for (name, ip) in network_interfaces {
+            info!("iface: {} / {:?}", name, ip);
+            if !is_host_candidate(&ip) {
+                continue;
+            }
+
+            let socket = match UdpSocket::bind(SocketAddr::new(ip, 0)).await {
+                Ok(socket) => socket,
+                Err(e) => {
+                    warn!("failed to bind {} ({}): {:?}", ip, name, e);
+                    continue;
+                }
+            };
+            let addr = socket
+                .local_addr()
+                .map_err(|e| WebrtcError::NetworkError(e.into()))?;
+            info!("local socket address: {:?}", addr);
+
+            rtc.add_local_candidate(
+                Candidate::host(addr, Protocol::Udp).expect("Failed to create local candidate"),
+            );
+            sockets.push(HostSocket { socket, addr });
+
+            // Passive and active ICE-TCP for when UDP is blocked
+            match tcp.listen(ip).await {
+                Ok(tcp_addr) => {
+                    let active = SocketAddr::new(ip, tcp::ACTIVE_PORT);
+                    for addr in [tcp_addr, active] {
+                        if let Ok(candidate) = Candidate::host(addr, Protocol::Tcp) {
+                            rtc.add_local_candidate(candidate);
                         }
                     }
-                    IpAddr::V6(_ip6) => {}
                 }
+                Err(e) => warn!("failed to listen on {} ({}): {:?}", ip, name, e),
             }
-        } else {
-            return Err(WebrtcError::NoCandidates);
         }
 
-        let Some(local_socket_addr) = local_socket_addr else {
+        if sockets.is_empty() {
             return Err(WebrtcError::NoCandidates);
-        };
+        }
 
         Ok(Self {
-            socket,
-            local_socket_addr,
+            sockets,
+            tcp,
             rtc,
             buf: [0; 1500],
             video_mid: None,
-            _audio_mid: None,
+            audio_mid: None,
+            resource_url: None,
+            token: None,
+            etag: None,
+            local_sdp: String::new(),
+            remote_sdp: String::new(),
+            turn: None,
+            last_keyframe_request: None,
+            ice_restarts: 0,
+            rids: Vec::new(),
+            simulcast: false,
         })
     }
 
+    /// POST an offer to a WHIP or WHEP endpoint and apply its answer. Sent
+    /// video is offered as one simulcast layer per RID in `rids`
     pub async fn send_whip_request(
         &mut self,
         url: &str,
         token: &Option<String>,
         direction: RtcDirection,
+        audio: bool,
+        rids: &[&str],
     ) -> Result<(), WebrtcError> {
         // Add receive tracks and generate an offer
         let mut change = self.rtc.sdp_api();
@@ -119,10 +287,23 @@ impl Client {
             Some("video_0".to_string()),
             Some("video_0".to_string()),
         ));
+        if audio {
+            // Same stream id as the video so receivers lip sync the two tracks
+            self.audio_mid = Some(change.add_media(
+                MediaKind::Audio,
+                direction,
+                Some("video_0".to_string()),
+                Some("audio_0".to_string()),
+            ));
+        }
 
         let (offer, pending) = change.apply().ok_or(WebrtcError::SdpError)?;
 
-        let offer_str = offer.to_sdp_string();
+        let mut offer_str = with_tcptype(&offer.to_sdp_string());
+        if let (Some(mid), false) = (self.video_mid, rids.is_empty()) {
+            offer_str = add_simulcast(&offer_str, mid, rids);
+            self.rids = rids.iter().map(|rid| rid.to_string()).collect();
+        }
         info!("offer: {}", offer_str);
         info!("token: {:?}", token);
         info!("url: {}", url);
@@ -186,6 +367,30 @@ impl Client {
         }
 
         info!("headers: {:?}", res.headers());
+        if let Some(location) = res.headers().get(LOCATION) {
+            let location = location
+                .to_str()
+                .map_err(|e| WebrtcError::ServerError(e.into()))?;
+            // Location may be relative to the URL that answered the offer
+            self.resource_url = Some(
+                next_url
+                    .join(location)
+                    .map_err(|e| WebrtcError::ServerError(e.into()))?,
+            );
+            self.token = token.clone();
+            self.etag = header_string(res.headers(), ETAG);
+        } else {
+            warn!("server did not return a Location, session can't be deleted");
+        }
+
+        let link_ice_servers: Vec<IceServer> = res
+            .headers()
+            .get_all(LINK)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(IceServer::from_link_header)
+            .collect();
+
         let answer = res
             .text()
             .await
@@ -198,20 +403,328 @@ impl Client {
                 SdpAnswer::from_sdp_string(&answer).map_err(|_| WebrtcError::SdpError)?,
             )
             .map_err(|_| WebrtcError::SdpError)?;
+        // str0m offers RTX for every video codec, lost packets are only
+        // resent if the server kept it for the codec we send
+        if let Some(writer) = self.video_mid.and_then(|mid| self.rtc.writer(mid)) {
+            let rtx = writer
+                .payload_params()
+                .any(|p| p.spec().codec == Codec::H264 && p.resend().is_some());
+            if !rtx {
+                warn!("RTX was not negotiated, lost video packets will not be resent");
+            }
+        }
+        if let (Some(mid), false) = (self.video_mid, self.rids.is_empty()) {
+            let accepted = accepted_rids(&answer, mid, &self.rids);
+            self.simulcast = !accepted.is_empty();
+            if self.simulcast {
+                // str0m only knows the stream from its own offer, each layer
+                // gets an SSRC of its own that is tagged with the RID
+                let mut api = self.rtc.direct_api();
+                for rid in &accepted {
+                    let ssrc = api.new_ssrc();
+                    let rtx = api.new_ssrc();
+                    api.declare_stream_tx(ssrc, Some(rtx), mid, Some(rid.as_str().into()));
+                }
+                self.rids = accepted;
+            } else {
+                warn!("server did not accept simulcast, only sending the full size layer");
+                self.rids.truncate(1);
+            }
+        }
+        self.local_sdp = offer_str;
+        self.remote_sdp = answer;
+
+        // The server's own ICE servers are only known now, trickle what they give us
+        self.gather_candidates(&link_ice_servers).await;
+
+        Ok(())
+    }
+
+    /// Gather server reflexive and relayed candidates from STUN and TURN servers
+    pub async fn gather_candidates(&mut self, ice_servers: &[IceServer]) {
+        for server in ice_servers {
+            if let Err(err) = self.gather_from(server).await {
+                warn!(
+                    "ICE server {}:{} failed: {:?}",
+                    server.host, server.port, err
+                );
+            }
+        }
+    }
+
+    async fn gather_from(&mut self, server: &IceServer) -> Result<(), WebrtcError> {
+        let server_addrs = server.resolve().await?;
+
+        if !server.turn {
+            let mut candidates = Vec::new();
+            let mut mapped_addrs = Vec::new();
+            for host in &self.sockets {
+                let Some(server_addr) = server_addrs
+                    .iter()
+                    .find(|addr| addr.is_ipv4() == host.addr.is_ipv4())
+                else {
+                    continue;
+                };
+
+                let mapped = ice::binding(&host.socket, *server_addr).await?;
+                info!("server reflexive address {} from {}", mapped, server_addr);
+                // Interfaces behind the same NAT all map to one address
+                if mapped != host.addr && !mapped_addrs.contains(&mapped) {
+                    mapped_addrs.push(mapped);
+                    candidates.push(
+                        Candidate::server_reflexive(mapped, host.addr, Protocol::Udp)
+                            .map_err(|e| WebrtcError::WebrtcError(e.into()))?,
+                    );
+                }
+            }
+
+            for candidate in candidates {
+                self.add_local_candidate(candidate).await?;
+            }
+            return Ok(());
+        }
+
+        if self.turn.is_some() {
+            info!("already relaying, skipping {}:{}", server.host, server.port);
+            return Ok(());
+        }
+        let (Some(username), Some(credential)) = (&server.username, &server.credential) else {
+            return Err(WebrtcError::ServerError(
+                "TURN server needs a username and credential".into(),
+            ));
+        };
+
+        // Reach the relay from the first interface that shares a family with it
+        let Some((server_addr, base_ip)) = self
+            .sockets
+            .iter()
+            .flat_map(|host| {
+                server_addrs
+                    .iter()
+                    .filter(|addr| addr.is_ipv4() == host.addr.is_ipv4())
+                    .map(|addr| (*addr, host.addr.ip()))
+            })
+            .next()
+        else {
+            return Err(WebrtcError::NetworkError(
+                format!("no local address can reach {}", server.host).into(),
+            ));
+        };
+
+        // Relayed traffic gets a socket of its own so Transmits can be told
+        // apart, on the same interface as the host candidate it is based on
+        let socket = UdpSocket::bind(SocketAddr::new(base_ip, 0))
+            .await
+            .map_err(|e| WebrtcError::NetworkError(e.into()))?;
+        let turn = TurnAllocation::allocate(socket, server_addr, username, credential).await?;
+        let candidate = Candidate::relayed(turn.relayed, Protocol::Udp)
+            .map_err(|e| WebrtcError::WebrtcError(e.into()))?;
+        self.turn = Some(turn);
+
+        self.add_local_candidate(candidate).await
+    }
+
+    /// Close the PeerConnection and DELETE the session resource on the server
+    pub async fn close(&mut self) -> Result<(), WebrtcError> {
+        self.rtc.disconnect();
+        if let Some(turn) = self.turn.take() {
+            let _ = turn.socket.send_to(&turn.deallocate(), turn.server).await;
+        }
+        if let Some(resource_url) = self.resource_url.take() {
+            delete_resource(resource_url, self.token.take()).await?;
+        }
 
         Ok(())
     }
 
     pub fn accept_whip_request(&mut self, offer: String) -> Result<String, WebrtcError> {
-        let offer = SdpOffer::from_sdp_string(&offer).map_err(|_| WebrtcError::SdpError)?;
-        if let Ok(answer) = self.rtc.sdp_api().accept_offer(offer) {
-            return Ok(answer.to_sdp_string());
+        let sdp_offer = SdpOffer::from_sdp_string(&offer).map_err(|_| WebrtcError::SdpError)?;
+        // A well formed offer we can't answer, e.g. without any usable media
+        let answer = self
+            .rtc
+            .sdp_api()
+            .accept_offer(sdp_offer)
+            .map_err(|e| WebrtcError::WebrtcError(e.into()))?;
+
+        self.remote_sdp = offer;
+        self.local_sdp = with_tcptype(&answer.to_sdp_string());
+        Ok(self.local_sdp.clone())
+    }
+
+    /// Add a candidate found after the offer went out, trickling it to the
+    /// server if the session is already established
+    pub async fn add_local_candidate(&mut self, candidate: Candidate) -> Result<(), WebrtcError> {
+        let fragment = IceFragment {
+            candidates: vec![candidate_with_tcptype(&candidate.to_sdp_string())],
+            ..IceFragment::parse(&self.local_sdp)
+        };
+        self.rtc.add_local_candidate(candidate);
+
+        if self.resource_url.is_none() {
+            return Ok(());
+        }
+
+        let if_match = self.etag.clone().unwrap_or_else(|| "*".to_string());
+        let res = self
+            .patch_resource(fragment.to_sdpfrag(&self.local_sdp), &if_match)
+            .await?;
+        match res.status() {
+            reqwest::StatusCode::OK | reqwest::StatusCode::NO_CONTENT => Ok(()),
+            // Trickle is optional, the candidate still goes out with an ICE restart
+            reqwest::StatusCode::METHOD_NOT_ALLOWED | reqwest::StatusCode::NOT_IMPLEMENTED => {
+                warn!("server does not support trickle ICE");
+                Ok(())
+            }
+            status => Err(WebrtcError::ServerError(
+                format!("PATCH failed with status: {}", status).into(),
+            )),
+        }
+    }
+
+    /// Restart ICE with fresh credentials, exchanged with the server over PATCH
+    /// Restart ICE after the connection dropped, retrying a failed restart.
+    /// Gives up once MAX_ICE_RESTARTS in a row haven't brought it back
+    pub async fn reconnect(&mut self) -> Result<(), WebrtcError> {
+        if self.resource_url.is_none() {
+            return Err(WebrtcError::ServerError(
+                "no session to restart ICE on".into(),
+            ));
+        }
+
+        loop {
+            if self.ice_restarts >= MAX_ICE_RESTARTS {
+                return Err(WebrtcError::ServerError(
+                    format!("no connection after {} ICE restarts", MAX_ICE_RESTARTS).into(),
+                ));
+            }
+            if self.ice_restarts > 0 {
+                tokio::time::sleep(ICE_RESTART_DELAY).await;
+            }
+            self.ice_restarts += 1;
+
+            match self.ice_restart().await {
+                Ok(()) => return Ok(()),
+                Err(err) => warn!("ICE restart {} failed: {:?}", self.ice_restarts, err),
+            }
+        }
+    }
+
+    async fn ice_restart(&mut self) -> Result<(), WebrtcError> {
+        if self.resource_url.is_none() {
+            return Err(WebrtcError::ServerError(
+                "no session to restart ICE on".into(),
+            ));
+        }
+
+        let mut change = self.rtc.sdp_api();
+        change.ice_restart(true);
+        let (offer, pending) = change.apply().ok_or(WebrtcError::SdpError)?;
+        let offer_str = with_tcptype(&offer.to_sdp_string());
+
+        // A restart changes the ETag, so it is made against any version
+        let fragment = IceFragment::parse(&offer_str).to_sdpfrag(&offer_str);
+        let res = self.patch_resource(fragment, "*").await?;
+        if res.status() != reqwest::StatusCode::OK {
+            return Err(WebrtcError::ServerError(
+                format!("ICE restart failed with status: {}", res.status()).into(),
+            ));
         }
 
-        return Err(WebrtcError::SdpError);
+        if let Some(etag) = header_string(res.headers(), ETAG) {
+            self.etag = Some(etag);
+        }
+        let fragment = res
+            .text()
+            .await
+            .map_err(|e| WebrtcError::ServerError(e.into()))?;
+        let answer = IceFragment::parse(&fragment).apply_to(&self.remote_sdp);
+
+        self.rtc
+            .sdp_api()
+            .accept_answer(
+                pending,
+                SdpAnswer::from_sdp_string(&answer).map_err(|_| WebrtcError::SdpError)?,
+            )
+            .map_err(|_| WebrtcError::SdpError)?;
+        self.local_sdp = offer_str;
+        self.remote_sdp = answer;
+
+        info!("ICE restarted");
+        Ok(())
+    }
+
+    /// Apply a PATCH from the remote peer. Trickled candidates are added as is,
+    /// new credentials restart ICE and our own fragment is returned for them
+    pub fn accept_ice_fragment(&mut self, fragment: &str) -> Result<Option<String>, WebrtcError> {
+        let fragment = IceFragment::parse(fragment);
+        let remote = IceFragment::parse(&self.remote_sdp);
+
+        if fragment.ufrag.is_some() && fragment.ufrag != remote.ufrag {
+            let offer = fragment.apply_to(&self.remote_sdp);
+            let answer = self
+                .rtc
+                .sdp_api()
+                .accept_offer(SdpOffer::from_sdp_string(&offer).map_err(|_| WebrtcError::SdpError)?)
+                .map_err(|_| WebrtcError::SdpError)?
+                .to_sdp_string();
+            self.remote_sdp = offer;
+            self.local_sdp = with_tcptype(&answer);
+
+            info!("ICE restarted by remote");
+            return Ok(Some(
+                IceFragment::parse(&self.local_sdp).to_sdpfrag(&self.local_sdp),
+            ));
+        }
+
+        for candidate in fragment.candidates {
+            let candidate =
+                Candidate::from_sdp_string(&candidate).map_err(|_| WebrtcError::SdpError)?;
+            info!("remote candidate: {:?}", candidate);
+            self.rtc.add_remote_candidate(candidate);
+        }
+
+        Ok(None)
+    }
+
+    async fn patch_resource(
+        &self,
+        fragment: String,
+        if_match: &str,
+    ) -> Result<reqwest::Response, WebrtcError> {
+        let resource_url = self
+            .resource_url
+            .clone()
+            .ok_or_else(|| WebrtcError::ServerError("no session resource".into()))?;
+        info!(
+            "PATCH {} If-Match: {}\n{}",
+            resource_url, if_match, fragment
+        );
+
+        let mut request = reqwest::Client::new()
+            .patch(resource_url)
+            .header(CONTENT_TYPE, "application/trickle-ice-sdpfrag")
+            .header(IF_MATCH, if_match)
+            .header(USER_AGENT, "bitwhip")
+            .body(fragment);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+
+        request
+            .send()
+            .await
+            .map_err(|e| WebrtcError::ServerError(e.into()))
     }
 
     pub async fn recv<'a>(&mut self) -> Result<WebrtcEvent, WebrtcError> {
+        if let Some(turn) = &mut self.turn {
+            if let Some(refresh) = turn.poll_refresh(Instant::now()) {
+                if let Err(e) = turn.socket.send_to(&refresh, turn.server).await {
+                    warn!("failed to refresh TURN allocation: {:?}", e);
+                }
+            }
+        }
+
         trace!("recv poll_output()");
         let timeout = match self
             .rtc
@@ -227,15 +740,33 @@ impl Client {
                     info!("ice connection state change: {:?}", state);
                     match state {
                         IceConnectionState::Disconnected => return Ok(WebrtcEvent::Disconnected),
+                        IceConnectionState::Connected | IceConnectionState::Completed => {
+                            self.ice_restarts = 0;
+                            return Ok(WebrtcEvent::Continue);
+                        }
                         _ => return Ok(WebrtcEvent::Continue),
                     }
                 }
                 Event::MediaIngressStats(stats) => {
-                    info!("egress stats: {:?}", stats);
+                    info!(
+                        "{:?} received {} packets, {:.1}% lost, {} NACKs sent for resends",
+                        stats.mid,
+                        stats.packets,
+                        stats.loss.unwrap_or(0.0) * 100.0,
+                        stats.nacks
+                    );
+                    debug!("ingress stats: {:?}", stats);
                     return Ok(WebrtcEvent::Continue);
                 }
                 Event::MediaEgressStats(stats) => {
-                    info!("egress stats: {:?}", stats);
+                    info!(
+                        "{:?} sent {} packets, {:.1}% lost, {} NACKs received",
+                        stats.mid,
+                        stats.packets,
+                        stats.loss.unwrap_or(0.0) * 100.0,
+                        stats.nacks
+                    );
+                    debug!("egress stats: {:?}", stats);
                     return Ok(WebrtcEvent::Continue);
                 }
                 Event::PeerStats(stats) => {
@@ -245,9 +776,23 @@ impl Client {
                 Event::MediaData(media) => {
                     return Ok(WebrtcEvent::Media(media));
                 }
+                Event::KeyframeRequest(request) => {
+                    debug!("keyframe request: {:?}", request);
+                    return Ok(WebrtcEvent::KeyframeRequest);
+                }
+                Event::EgressBitrateEstimate(estimate) => {
+                    debug!("bitrate estimate: {:?}", estimate);
+                    let (BweKind::Twcc(bitrate) | BweKind::Remb(_, bitrate)) = estimate;
+                    return Ok(WebrtcEvent::BitrateEstimate(bitrate.as_u64()));
+                }
                 Event::MediaAdded(media) => {
                     info!("Media Added: {:?}", media);
                     info!("Codec Config: {:?}", self.rtc.codec_config());
+                    // Media from an offer we accepted, remember where to send to
+                    match media.kind {
+                        MediaKind::Video => self.video_mid.get_or_insert(media.mid),
+                        MediaKind::Audio => self.audio_mid.get_or_insert(media.mid),
+                    };
                     return Ok(WebrtcEvent::Continue);
                 }
                 _ => {
@@ -256,8 +801,45 @@ impl Client {
             },
             Output::Timeout(timeout) => timeout,
             Output::Transmit(send) => {
-                // Apply random packet loss to outbound traffic
-                if let Err(e) = self.socket.send_to(&send.contents, send.destination).await {
+                if send.proto == Protocol::Tcp {
+                    if let Err(e) = self
+                        .tcp
+                        .send(send.source, send.destination, &send.contents)
+                        .await
+                    {
+                        debug!("sending to {} over TCP error {:?}", send.destination, e);
+                    }
+                    return Ok(WebrtcEvent::Continue);
+                }
+
+                if let Some(turn) = self
+                    .turn
+                    .as_mut()
+                    .filter(|turn| send.source == turn.relayed)
+                {
+                    for datagram in turn.send(send.destination, &send.contents) {
+                        if let Err(e) = turn.socket.send_to(&datagram, turn.server).await {
+                            debug!("relaying to {} error {:?}", send.destination, e);
+                        }
+                    }
+                    return Ok(WebrtcEvent::Continue);
+                }
+
+                // Send from the socket the source candidate belongs to
+                let host = self
+                    .sockets
+                    .iter()
+                    .find(|host| host.addr == send.source)
+                    .or_else(|| {
+                        self.sockets
+                            .iter()
+                            .find(|host| host.addr.is_ipv4() == send.destination.is_ipv4())
+                    });
+                let Some(host) = host else {
+                    debug!("no socket to send to {}", send.destination);
+                    return Ok(WebrtcEvent::Continue);
+                };
+                if let Err(e) = host.socket.send_to(&send.contents, send.destination).await {
                     debug!(
                         "sending to {} => {}, len {} error {:?}",
                         send.source,
@@ -282,44 +864,74 @@ impl Client {
             };
         }
 
-        let input = match tokio::time::timeout(duration, self.socket.recv_from(&mut self.buf)).await
-        {
-            Ok(Ok((n, source))) => {
-                // UDP data received.
-                info!(
-                    "received from {} => {}, len {}",
-                    source,
-                    SocketAddr::new(
-                        self.local_socket_addr.ip(),
-                        self.socket.local_addr().unwrap().port(),
-                    ),
-                    n
-                );
+        let owned_contents;
+        let input = tokio::select! {
+            (index, received) = recv_host(&self.sockets, &mut self.buf) => match received {
+                Ok((n, source)) => {
+                    // UDP data received, on the candidate its socket is bound to
+                    let destination = self.sockets[index].addr;
+                    info!("received from {} => {}, len {}", source, destination, n);
+                    Input::Receive(
+                        Instant::now(),
+                        Receive {
+                            proto: Protocol::Udp,
+                            source,
+                            destination,
+                            contents: (&self.buf[..n]).try_into().expect("should webrtc"),
+                        },
+                    )
+                }
+                Err(e) => match e.kind() {
+                    ErrorKind::ConnectionReset => return Ok(WebrtcEvent::Continue),
+                    _ => {
+                        error!("[TransportWebrtc] network error {:?}", e);
+                        return Err(WebrtcError::NetworkError(e.into()));
+                    }
+                },
+            },
+            received = recv_relay(&mut self.turn) => {
+                let turn = self.turn.as_mut().expect("only polled with a relay");
+                let peer_data = match received {
+                    Ok((n, source)) if source == turn.server => {
+                        let datagram = turn.buf[..n].to_vec();
+                        turn.receive(&datagram)
+                    }
+                    Ok(_) => None,
+                    Err(e) => {
+                        debug!("relay socket error {:?}", e);
+                        None
+                    }
+                };
+                let Some((source, data)) = peer_data else {
+                    return Ok(WebrtcEvent::Continue);
+                };
+
+                // Relayed data arrives as if it was sent straight to the relayed candidate
+                owned_contents = data;
                 Input::Receive(
                     Instant::now(),
                     Receive {
                         proto: Protocol::Udp,
                         source,
-                        destination: SocketAddr::new(
-                            self.local_socket_addr.ip(),
-                            self.socket.local_addr().unwrap().port(),
-                        ),
-                        contents: (&self.buf[..n]).try_into().expect("should webrtc"),
+                        destination: turn.relayed,
+                        contents: (&owned_contents[..]).try_into().expect("should webrtc"),
                     },
                 )
             }
-            Ok(Err(e)) => match e.kind() {
-                ErrorKind::ConnectionReset => return Ok(WebrtcEvent::Continue),
-                _ => {
-                    error!("[TransportWebrtc] network error {:?}", e);
-                    return Err(WebrtcError::NetworkError(e.into()));
-                }
-            },
-            Err(_e) => {
-                // Expected error for set_read_timeout().
-                // One for windows, one for the rest.
-                Input::Timeout(Instant::now())
+            (source, destination, data) = self.tcp.recv() => {
+                trace!("received from {} => {} over TCP, len {}", source, destination, data.len());
+                owned_contents = data;
+                Input::Receive(
+                    Instant::now(),
+                    Receive {
+                        proto: Protocol::Tcp,
+                        source,
+                        destination,
+                        contents: (&owned_contents[..]).try_into().expect("should webrtc"),
+                    },
+                )
             }
+            _ = tokio::time::sleep(duration) => Input::Timeout(Instant::now()),
         };
 
         // Input is either a Timeout or Receive of data. Both drive the state forward.
@@ -329,7 +941,56 @@ impl Client {
         return Ok(WebrtcEvent::Continue);
     }
 
-    pub fn send_video(&mut self, frame_data: Bytes, pts: Duration) -> Result<(), WebrtcError> {
+    /// Tell the bandwidth estimator what is being sent now and the most that
+    /// would be sent, which it probes towards with padding
+    pub fn set_bitrate(&mut self, current: u64, desired: u64) {
+        let mut bwe = self.rtc.bwe();
+        bwe.set_current_bitrate(Bitrate::bps(current));
+        bwe.set_desired_bitrate(Bitrate::bps(desired));
+    }
+
+    /// Ask the sender of our incoming video for a keyframe with a PLI. While
+    /// one is outstanding further requests are dropped
+    pub fn request_keyframe(&mut self) {
+        if self
+            .last_keyframe_request
+            .is_some_and(|at| at.elapsed() < KEYFRAME_REQUEST_INTERVAL)
+        {
+            return;
+        }
+
+        let Some(mid) = self.video_mid else {
+            return;
+        };
+        let mut api = self.rtc.direct_api();
+        let Some(stream) = api.stream_rx_by_mid(mid, None) else {
+            return;
+        };
+
+        debug!("requesting keyframe on {:?}", mid);
+        stream.request_keyframe(KeyframeRequestKind::Pli);
+        self.last_keyframe_request = Some(Instant::now());
+    }
+
+    /// The simulcast layers the server takes once the answer is in, only the
+    /// full size one if it didn't take simulcast
+    pub fn sent_rids(&self) -> &[String] {
+        &self.rids
+    }
+
+    /// Send an encoded frame, of the simulcast layer `rid` if there is one
+    pub fn send_video(
+        &mut self,
+        frame_data: Bytes,
+        pts: Duration,
+        rid: Option<&str>,
+    ) -> Result<(), WebrtcError> {
+        let rid = match rid {
+            // Layers the server didn't take are dropped
+            Some(rid) if !self.rids.iter().any(|taken| taken == rid) => return Ok(()),
+            Some(rid) if self.simulcast => Some(Rid::from(rid)),
+            _ => None,
+        };
         if let Some(mid) = self.video_mid {
             // TODO = maybe look this up once?
             let params = &self
@@ -341,8 +1002,11 @@ impl Client {
                         && p.spec().format.profile_level_id.unwrap_or(0) == 4382751
                 })
                 .cloned()
-                .unwrap();
-            if let Some(writer) = self.rtc.writer(mid) {
+                .ok_or(WebrtcError::SdpError)?;
+            if let Some(mut writer) = self.rtc.writer(mid) {
+                if let Some(rid) = rid {
+                    writer = writer.rid(rid);
+                }
                 let freq = params.spec().clock_rate;
                 let media_time: MediaTime = pts.into();
                 writer
@@ -359,4 +1023,232 @@ impl Client {
         }
         Ok(())
     }
+
+    pub fn send_audio(&mut self, frame_data: Bytes, pts: Duration) -> Result<(), WebrtcError> {
+        if let Some(mid) = self.audio_mid {
+            let params = &self
+                .rtc
+                .codec_config()
+                .find(|p| p.spec().codec == Codec::Opus)
+                .cloned()
+                .ok_or(WebrtcError::SdpError)?;
+            if let Some(writer) = self.rtc.writer(mid) {
+                let freq = params.spec().clock_rate;
+                let media_time: MediaTime = pts.into();
+                writer
+                    .write(
+                        params.pt(),
+                        Instant::now(),
+                        media_time.rebase(freq),
+                        frame_data,
+                    )
+                    .map_err(|e| WebrtcError::SendError(e.to_string()))?;
+            }
+        } else {
+            warn!("trying to send audio without mid");
+        }
+        Ok(())
+    }
+}
+
+/// Receive on whichever host socket has a datagram first
+async fn recv_host(
+    sockets: &[HostSocket],
+    buf: &mut [u8],
+) -> (usize, std::io::Result<(usize, SocketAddr)>) {
+    poll_fn(|cx| {
+        for (index, host) in sockets.iter().enumerate() {
+            let mut read_buf = ReadBuf::new(buf);
+            if let Poll::Ready(received) = host.socket.poll_recv_from(cx, &mut read_buf) {
+                let n = read_buf.filled().len();
+                return Poll::Ready((index, received.map(|source| (n, source))));
+            }
+        }
+
+        Poll::Pending
+    })
+    .await
+}
+
+fn is_host_candidate(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip4) => !ip4.is_loopback() && !ip4.is_link_local(),
+        // Link local IPv6 needs a scope id that candidates can't carry
+        IpAddr::V6(ip6) => {
+            !ip6.is_loopback() && !ip6.is_unspecified() && ip6.segments()[0] & 0xffc0 != 0xfe80
+        }
+    }
+}
+
+async fn recv_relay(turn: &mut Option<TurnAllocation>) -> std::io::Result<(usize, SocketAddr)> {
+    match turn {
+        Some(turn) => turn.socket.recv_from(&mut turn.buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let Some(resource_url) = self.resource_url.take() else {
+            return;
+        };
+
+        // close() wasn't awaited. Drop can't be async and may be running on a
+        // runtime that is shutting down, so DELETE from a runtime of its own
+        let token = self.token.take();
+        let result = std::thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(|e| WebrtcError::NetworkError(e.into()))?
+                .block_on(delete_resource(resource_url, token))
+        })
+        .join();
+
+        if let Ok(Err(err)) = result {
+            error!("failed to delete session: {:?}", err);
+        }
+    }
+}
+
+/// Announce send simulcast on the m-line of `mid`, one layer per RID. The
+/// layers are told apart by the RID header extension on every packet, so the
+/// a=ssrc lines of str0m's own stream without a RID are left out
+fn add_simulcast(sdp: &str, mid: Mid, rids: &[&str]) -> String {
+    let mid_line = format!("a=mid:{}", mid);
+    let mut out = String::with_capacity(sdp.len());
+    for section in media_sections(sdp) {
+        let layered = section.iter().any(|line| line.trim_end() == mid_line);
+        for line in section {
+            if layered && (line.starts_with("a=ssrc:") || line.starts_with("a=ssrc-group:")) {
+                continue;
+            }
+            out.push_str(line);
+            if layered && line.trim_end() == mid_line {
+                for rid in rids {
+                    out.push_str(&format!("a=rid:{} send\r\n", rid));
+                }
+                out.push_str(&format!("a=simulcast:send {}\r\n", rids.join(";")));
+            }
+        }
+    }
+
+    out
+}
+
+/// The RIDs of `rids` that the m-line of `mid` in `answer` receives as
+/// simulcast layers, none if it didn't accept simulcast. Paused layers are
+/// left out
+fn accepted_rids(answer: &str, mid: Mid, rids: &[String]) -> Vec<String> {
+    let mid_line = format!("a=mid:{}", mid);
+    let Some(section) = media_sections(answer)
+        .into_iter()
+        .find(|section| section.iter().any(|line| line.trim_end() == mid_line))
+    else {
+        return Vec::new();
+    };
+    let Some(simulcast) = section
+        .iter()
+        .find_map(|line| line.trim_end().strip_prefix("a=simulcast:"))
+    else {
+        return Vec::new();
+    };
+
+    let Some(layers) = simulcast
+        .split_whitespace()
+        .skip_while(|dir| *dir != "recv")
+        .nth(1)
+    else {
+        return Vec::new();
+    };
+    let layers: Vec<&str> = layers.split([';', ',']).collect();
+    rids.iter()
+        .filter(|rid| layers.contains(&rid.as_str()))
+        .cloned()
+        .collect()
+}
+
+/// Split `sdp` into its lines, line endings kept, grouped by m-line. The
+/// session level lines come first
+fn media_sections(sdp: &str) -> Vec<Vec<&str>> {
+    let mut sections = vec![Vec::new()];
+    for line in sdp.split_inclusive('\n') {
+        if line.starts_with("m=") {
+            sections.push(Vec::new());
+        }
+        sections.last_mut().unwrap().push(line);
+    }
+
+    sections
+}
+
+/// str0m leaves the tcptype of RFC 6544 out of TCP candidates, add it to
+/// every one in `sdp`
+fn with_tcptype(sdp: &str) -> String {
+    let lines: Vec<String> = sdp
+        .lines()
+        .map(|line| match line.strip_prefix("a=") {
+            Some(candidate) if candidate.starts_with("candidate:") => {
+                format!("a={}", candidate_with_tcptype(candidate))
+            }
+            _ => line.to_string(),
+        })
+        .collect();
+
+    lines.join("\r\n") + "\r\n"
+}
+
+/// Mark a TCP candidate active if it is on the discard port, passive otherwise
+fn candidate_with_tcptype(candidate: &str) -> String {
+    let mut fields: Vec<&str> = candidate.split(' ').collect();
+    let is_tcp = fields
+        .get(2)
+        .is_some_and(|proto| proto.eq_ignore_ascii_case("tcp"));
+    let Some(typ) = fields.iter().position(|field| *field == "typ") else {
+        return candidate.to_string();
+    };
+    if !is_tcp || fields.contains(&"tcptype") || typ + 2 > fields.len() {
+        return candidate.to_string();
+    }
+
+    let tcptype = match fields.get(5).map(|port| port.parse::<u16>()) {
+        Some(Ok(tcp::ACTIVE_PORT)) => "active",
+        _ => "passive",
+    };
+    fields.splice(typ + 2..typ + 2, ["tcptype", tcptype]);
+    fields.join(" ")
+}
+
+fn header_string(
+    headers: &reqwest::header::HeaderMap,
+    name: reqwest::header::HeaderName,
+) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+async fn delete_resource(
+    resource_url: reqwest::Url,
+    token: Option<String>,
+) -> Result<(), WebrtcError> {
+    info!("deleting session {}", resource_url);
+
+    let mut request = reqwest::Client::new()
+        .delete(resource_url)
+        .header(USER_AGENT, "bitwhip");
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+
+    let res = request
+        .send()
+        .await
+        .map_err(|e| WebrtcError::ServerError(e.into()))?;
+    if !res.status().is_success() {
+        warn!("DELETE failed with status: {}", res.status());
+    }
+
+    Ok(())
 }
@@ -1,16 +1,35 @@
 use anyhow::{anyhow, bail, Context, Result};
-use ffmpeg::ffi::AVCodecContext;
-use ffmpeg::{codec::Context as CodecContext, encoder::Video, Frame, Packet};
+use ffmpeg::ffi::{AVBufferRef, AVCodecContext, AVHWDeviceType, AVHWFramesContext};
+use ffmpeg::{
+    codec::Context as CodecContext,
+    encoder::{Audio, Video},
+    filter,
+    format::{sample, Pixel, Sample},
+    frame, picture,
+    software::scaling,
+    ChannelLayout, Packet, Rational,
+};
 use ffmpeg_next as ffmpeg;
 use log::info;
 use std::{
     collections::HashMap,
     ffi::{c_void, CString},
+    ptr,
+    time::{Duration, Instant},
 };
 
+// Keyframe requests closer together than this are held back, every viewer
+// that joins or loses a packet asks for one
+const MIN_KEYFRAME_INTERVAL: Duration = Duration::from_millis(500);
+
 pub struct Encoder {
     encoder: Video,
     dimensions: (u32, u32),
+    converter: Option<scaling::Context>,
+    keyframe_requested: bool,
+    last_keyframe: Option<Instant>,
+    // Whether the bitrate can be changed without reopening the encoder
+    live_bit_rate: bool,
 }
 
 impl Encoder {
@@ -22,6 +41,8 @@ impl Encoder {
     where
         F: FnOnce(&mut ffmpeg::encoder::video::Video) -> Result<()>,
     {
+        // These pick up a changed bit_rate on the next frame
+        let live_bit_rate = matches!(encoder, "libx264" | "h264_nvenc" | "h264_qsv");
         let codec = ffmpeg::encoder::find_by_name(encoder)
             .ok_or_else(|| anyhow!("Missing encoder {}", encoder))?;
 
@@ -43,20 +64,129 @@ impl Encoder {
         Ok(Encoder {
             encoder: encoder.open()?,
             dimensions,
+            converter: None,
+            keyframe_requested: false,
+            last_keyframe: None,
+            live_bit_rate,
         })
     }
 
-    pub fn encode(&mut self, frame: &Frame) -> Result<Option<Packet>> {
-        self.encoder.send_frame(frame)?;
+    pub fn bit_rate(&self) -> usize {
+        unsafe { (*self.encoder.as_ptr()).bit_rate as usize }
+    }
+
+    /// Retarget the bitrate from the next frame on. Returns false if this
+    /// encoder only takes a new bitrate when it is reopened
+    pub fn set_bit_rate(&mut self, bit_rate: usize) -> bool {
+        if !self.live_bit_rate {
+            return false;
+        }
+
+        unsafe { (*self.encoder.as_mut_ptr()).bit_rate = bit_rate as i64 };
+        true
+    }
+
+    /// Make one of the next frames an IDR, as soon as the rate limit allows
+    pub fn request_keyframe(&mut self) {
+        self.keyframe_requested = true;
+    }
+
+    pub fn encode(&mut self, frame: &mut frame::Video) -> Result<Option<Packet>> {
+        let keyframe_due = self.keyframe_requested
+            && self
+                .last_keyframe
+                .is_none_or(|at| at.elapsed() >= MIN_KEYFRAME_INTERVAL);
+        if keyframe_due {
+            frame.set_kind(picture::Type::I);
+            self.keyframe_requested = false;
+        } else {
+            frame.set_kind(picture::Type::None);
+        }
+
+        // Frames already in the encoder's format (e.g. D3D11 textures) are
+        // passed through untouched, everything else is downloaded, converted
+        // and uploaded as needed
+        if frame.format() == self.encoder.format() {
+            self.encoder.send_frame(frame)?;
+        } else {
+            self.send_converted(frame)?;
+        }
 
         let mut packet = Packet::empty();
         if self.encoder.receive_packet(&mut packet).is_ok() {
+            if packet.is_key() {
+                // A keyframe from the regular GOP answers pending requests too
+                self.keyframe_requested = false;
+                self.last_keyframe = Some(Instant::now());
+            }
             return Ok(Some(packet));
         }
 
         Ok(None)
     }
 
+    fn send_converted(&mut self, frame: &frame::Video) -> Result<()> {
+        let downloaded;
+        let frame = if unsafe { (*frame.as_ptr()).hw_frames_ctx.is_null() } {
+            frame
+        } else {
+            downloaded = download(frame)?;
+            &downloaded
+        };
+
+        let hw_frames = unsafe { (*self.encoder.as_ptr()).hw_frames_ctx };
+        let sw_format = if hw_frames.is_null() {
+            self.encoder.format()
+        } else {
+            unsafe { Pixel::from((*((*hw_frames).data as *const AVHWFramesContext)).sw_format) }
+        };
+
+        let converted;
+        let frame = if frame.format() == sw_format {
+            frame
+        } else {
+            converted = self.convert(frame, sw_format)?;
+            &converted
+        };
+
+        if hw_frames.is_null() {
+            self.encoder.send_frame(frame)?;
+        } else {
+            self.encoder.send_frame(&upload(frame, hw_frames)?)?;
+        }
+
+        Ok(())
+    }
+
+    fn convert(&mut self, frame: &frame::Video, format: Pixel) -> Result<frame::Video> {
+        let stale = match &self.converter {
+            Some(converter) => converter.input().format != frame.format(),
+            None => true,
+        };
+        if stale {
+            info!(
+                "Converting frames from {:?} to {:?}",
+                frame.format(),
+                format
+            );
+            self.converter.replace(ffmpeg::software::converter(
+                self.dimensions,
+                frame.format(),
+                format,
+            )?);
+        }
+
+        let mut converted = frame::Video::empty();
+        self.converter
+            .as_mut()
+            .unwrap()
+            .run(frame, &mut converted)?;
+        converted.set_pts(frame.pts());
+        converted.set_kind(frame.kind());
+
+        Ok(converted)
+    }
+
     unsafe fn set_option(context: *mut AVCodecContext, name: &str, val: &str) -> Result<()> {
         let name_c = CString::new(name).context("Error in CString")?;
         let val_c = CString::new(val).context("Error in CString")?;
@@ -76,3 +206,283 @@ impl Encoder {
         return self.dimensions;
     }
 }
+
+/// Shrinks frames through an ffmpeg scale filter, e.g. for the lower layers
+/// of a simulcast stream. Hardware frames are downloaded first
+pub struct Scaler {
+    divisor: u32,
+    graph: Option<filter::Graph>,
+    // Size and format the graph was built for
+    input: (u32, u32, Pixel),
+}
+
+impl Scaler {
+    /// Scale to 1/`divisor` of the width and height
+    pub fn new(divisor: u32) -> Self {
+        Self {
+            divisor,
+            graph: None,
+            input: (0, 0, Pixel::None),
+        }
+    }
+
+    pub fn scale(&mut self, frame: &frame::Video) -> Result<frame::Video> {
+        let downloaded;
+        let frame = if unsafe { (*frame.as_ptr()).hw_frames_ctx.is_null() } {
+            frame
+        } else {
+            downloaded = download(frame)?;
+            &downloaded
+        };
+
+        // Sources can change resolution midway, rebuild the graph to match
+        let input = (frame.width(), frame.height(), frame.format());
+        if self.graph.is_none() || self.input != input {
+            self.graph = Some(Self::create_graph(input, self.divisor)?);
+            self.input = input;
+        }
+        let graph = self.graph.as_mut().unwrap();
+        graph.get("in").unwrap().source().add(frame)?;
+
+        let mut scaled = frame::Video::empty();
+        graph.get("out").unwrap().sink().frame(&mut scaled)?;
+        scaled.set_pts(frame.pts());
+        scaled.set_kind(frame.kind());
+
+        Ok(scaled)
+    }
+
+    fn create_graph(
+        (width, height, format): (u32, u32, Pixel),
+        divisor: u32,
+    ) -> Result<filter::Graph> {
+        let mut graph = filter::Graph::new();
+
+        let buffer =
+            filter::find("buffer").ok_or_else(|| anyhow!("Failed to find buffer filter"))?;
+        let buffer_sink = filter::find("buffersink")
+            .ok_or_else(|| anyhow!("Failed to find buffersink filter"))?;
+
+        // Encoders want even dimensions for 4:2:0
+        let (scaled_width, scaled_height) = ((width / divisor) & !1, (height / divisor) & !1);
+        info!("Scaling video from {width}x{height} to {scaled_width}x{scaled_height}");
+        graph.add(
+            &buffer,
+            "in",
+            &format!(
+                "video_size={width}x{height}:pix_fmt={}:time_base=1/1:pixel_aspect=1/1",
+                ffmpeg::ffi::AVPixelFormat::from(format) as i32
+            ),
+        )?;
+        graph.add(&buffer_sink, "out", "")?;
+        graph
+            .output("in", 0)?
+            .input("out", 0)?
+            .parse(&format!("scale={scaled_width}:{scaled_height}"))?;
+        graph.validate()?;
+
+        Ok(graph)
+    }
+}
+
+/// Opus encoder that resamples whatever the audio source yields to 48kHz
+/// stereo and encodes it in 20ms packets
+pub struct AudioEncoder {
+    encoder: Audio,
+    graph: Option<filter::Graph>,
+    samples: i64,
+}
+
+impl AudioEncoder {
+    pub const SAMPLE_RATE: u32 = 48000;
+    const FRAME_SIZE: u32 = 960;
+
+    /// `expected_loss` is the packet loss in percent that Opus in-band FEC
+    /// protects against, 0 turns FEC off
+    pub fn new(bit_rate: usize, expected_loss: u8) -> Result<Self> {
+        let codec = ffmpeg::encoder::find_by_name("libopus")
+            .ok_or_else(|| anyhow!("Missing encoder libopus"))?;
+
+        let mut encoder = CodecContext::new_with_codec(codec).encoder().audio()?;
+        encoder.set_rate(Self::SAMPLE_RATE as i32);
+        encoder.set_format(Sample::I16(sample::Type::Packed));
+        encoder.set_channel_layout(ChannelLayout::STEREO);
+        encoder.set_bit_rate(bit_rate);
+        encoder.set_time_base(Rational::new(1, Self::SAMPLE_RATE as i32));
+
+        unsafe {
+            // FEC is carried by the SILK layer, which lowdelay leaves out
+            let application = if expected_loss > 0 {
+                "voip"
+            } else {
+                "lowdelay"
+            };
+            Encoder::set_option(encoder.as_mut_ptr(), "application", application)?;
+            Encoder::set_option(encoder.as_mut_ptr(), "frame_duration", "20")?;
+            if expected_loss > 0 {
+                Encoder::set_option(encoder.as_mut_ptr(), "fec", "1")?;
+                Encoder::set_option(
+                    encoder.as_mut_ptr(),
+                    "packet_loss",
+                    &expected_loss.to_string(),
+                )?;
+            }
+        }
+
+        Ok(AudioEncoder {
+            encoder: encoder.open()?,
+            graph: None,
+            samples: 0,
+        })
+    }
+
+    pub fn encode(&mut self, frame: &frame::Audio) -> Result<Vec<Packet>> {
+        if self.graph.is_none() {
+            self.graph = Some(Self::create_graph(frame)?);
+        }
+        let graph = self.graph.as_mut().unwrap();
+        graph.get("in").unwrap().source().add(frame)?;
+
+        let mut packets = Vec::new();
+        loop {
+            let mut resampled = frame::Audio::empty();
+            if graph
+                .get("out")
+                .unwrap()
+                .sink()
+                .frame(&mut resampled)
+                .is_err()
+            {
+                break;
+            }
+
+            // Timestamps count samples so RTP time advances exactly 20ms per packet
+            resampled.set_pts(Some(self.samples));
+            self.samples += resampled.samples() as i64;
+            self.encoder.send_frame(&resampled)?;
+
+            let mut packet = Packet::empty();
+            while self.encoder.receive_packet(&mut packet).is_ok() {
+                packets.push(packet);
+                packet = Packet::empty();
+            }
+        }
+
+        Ok(packets)
+    }
+
+    fn create_graph(frame: &frame::Audio) -> Result<filter::Graph> {
+        let mut graph = filter::Graph::new();
+
+        let buffer =
+            filter::find("abuffer").ok_or_else(|| anyhow!("Failed to find abuffer filter"))?;
+        let buffer_sink = filter::find("abuffersink")
+            .ok_or_else(|| anyhow!("Failed to find abuffersink filter"))?;
+
+        info!(
+            "Resampling audio from {} {}Hz {} channels",
+            frame.format().name(),
+            frame.rate(),
+            frame.channels()
+        );
+        graph.add(
+            &buffer,
+            "in",
+            &format!(
+                "time_base=1/{rate}:sample_rate={rate}:sample_fmt={}:channels={}",
+                frame.format().name(),
+                frame.channels(),
+                rate = frame.rate()
+            ),
+        )?;
+        graph.add(&buffer_sink, "out", "")?;
+        graph.output("in", 0)?.input("out", 0)?.parse(&format!(
+            "aresample={},aformat=sample_fmts=s16:channel_layouts=stereo,asetnsamples=n={}:p=0",
+            Self::SAMPLE_RATE,
+            Self::FRAME_SIZE
+        ))?;
+        graph.validate()?;
+
+        Ok(graph)
+    }
+}
+
+/// Allocate a hardware frames context for encoders that only accept frames
+/// living on the device, e.g. VAAPI
+pub fn create_hw_frames(
+    device_type: AVHWDeviceType,
+    format: Pixel,
+    sw_format: Pixel,
+    width: u32,
+    height: u32,
+) -> Result<*mut AVBufferRef> {
+    unsafe {
+        let mut device = ptr::null_mut();
+        let retval = ffmpeg::ffi::av_hwdevice_ctx_create(
+            &mut device,
+            device_type,
+            ptr::null(),
+            ptr::null_mut(),
+            0,
+        );
+        if retval < 0 {
+            bail!("av_hwdevice_ctx_create failed: {retval}");
+        }
+
+        let mut frames = ffmpeg::ffi::av_hwframe_ctx_alloc(device);
+        ffmpeg::ffi::av_buffer_unref(&mut device);
+        if frames.is_null() {
+            bail!("av_hwframe_ctx_alloc failed");
+        }
+
+        let frames_context = &mut *((*frames).data as *mut AVHWFramesContext);
+        frames_context.format = format.into();
+        frames_context.sw_format = sw_format.into();
+        frames_context.width = width as i32;
+        frames_context.height = height as i32;
+        frames_context.initial_pool_size = 20;
+
+        let retval = ffmpeg::ffi::av_hwframe_ctx_init(frames);
+        if retval < 0 {
+            ffmpeg::ffi::av_buffer_unref(&mut frames);
+            bail!("av_hwframe_ctx_init failed: {retval}");
+        }
+
+        Ok(frames)
+    }
+}
+
+fn download(frame: &frame::Video) -> Result<frame::Video> {
+    let mut downloaded = frame::Video::empty();
+    unsafe {
+        let retval =
+            ffmpeg::ffi::av_hwframe_transfer_data(downloaded.as_mut_ptr(), frame.as_ptr(), 0);
+        if retval < 0 {
+            bail!("av_hwframe_transfer_data failed: {retval}");
+        }
+    }
+    downloaded.set_pts(frame.pts());
+    downloaded.set_kind(frame.kind());
+
+    Ok(downloaded)
+}
+
+fn upload(frame: &frame::Video, hw_frames: *mut AVBufferRef) -> Result<frame::Video> {
+    let mut uploaded = frame::Video::empty();
+    unsafe {
+        let retval = ffmpeg::ffi::av_hwframe_get_buffer(hw_frames, uploaded.as_mut_ptr(), 0);
+        if retval < 0 {
+            bail!("av_hwframe_get_buffer failed: {retval}");
+        }
+
+        let retval =
+            ffmpeg::ffi::av_hwframe_transfer_data(uploaded.as_mut_ptr(), frame.as_ptr(), 0);
+        if retval < 0 {
+            bail!("av_hwframe_transfer_data failed: {retval}");
+        }
+    }
+    uploaded.set_pts(frame.pts());
+    uploaded.set_kind(frame.kind());
+
+    Ok(uploaded)
+}
@@ -0,0 +1,800 @@
+//! Just enough of STUN (RFC 8489) and TURN (RFC 8656) to gather server
+//! reflexive and relayed candidates over UDP
+
+use crate::client::WebrtcError;
+use openssl::{
+    hash::{hash, MessageDigest},
+    pkey::PKey,
+    sign::Signer,
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const ALLOCATE_REQUEST: u16 = 0x0003;
+const REFRESH_REQUEST: u16 = 0x0004;
+const CREATE_PERMISSION_REQUEST: u16 = 0x0008;
+const SEND_INDICATION: u16 = 0x0016;
+const DATA_INDICATION: u16 = 0x0017;
+const SUCCESS_RESPONSE: u16 = 0x0100;
+const ERROR_RESPONSE: u16 = 0x0110;
+
+const ATTR_USERNAME: u16 = 0x0006;
+const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+const ATTR_ERROR_CODE: u16 = 0x0009;
+const ATTR_LIFETIME: u16 = 0x000d;
+const ATTR_XOR_PEER_ADDRESS: u16 = 0x0012;
+const ATTR_DATA: u16 = 0x0013;
+const ATTR_REALM: u16 = 0x0014;
+const ATTR_NONCE: u16 = 0x0015;
+const ATTR_XOR_RELAYED_ADDRESS: u16 = 0x0016;
+const ATTR_REQUESTED_TRANSPORT: u16 = 0x0019;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+const UDP_TRANSPORT: u8 = 17;
+const DEFAULT_PORT: u16 = 3478;
+
+// Permissions last 5 minutes, refresh them a minute early
+const PERMISSION_REFRESH: Duration = Duration::from_secs(240);
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(500);
+const RETRANSMITS: usize = 4;
+
+/// A STUN or TURN server from `--ice-server` or a `Link: rel="ice-server"` header
+#[derive(Debug, Clone)]
+pub struct IceServer {
+    pub turn: bool,
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub credential: Option<String>,
+}
+
+impl FromStr for IceServer {
+    type Err = String;
+
+    /// Parses `stun:host[:port]` or `turn:[username:credential@]host[:port][?transport=udp]`
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = url
+            .split_once(':')
+            .ok_or_else(|| format!("{} is not a stun: or turn: URL", url))?;
+        let turn = match scheme {
+            "stun" => false,
+            "turn" => true,
+            "stuns" | "turns" => return Err(format!("{} servers are not supported", scheme)),
+            _ => return Err(format!("{} is not a stun: or turn: URL", url)),
+        };
+
+        let (rest, query) = rest.split_once('?').unwrap_or((rest, ""));
+        if query.split('&').any(|param| param == "transport=tcp") {
+            return Err("only UDP ICE servers are supported".to_string());
+        }
+
+        let (credentials, host_port) = match rest.rsplit_once('@') {
+            Some((credentials, host_port)) => (Some(credentials), host_port),
+            None => (None, rest),
+        };
+        let (username, credential) = match credentials.map(|c| c.split_once(':')) {
+            Some(Some((username, credential))) => {
+                (Some(username.to_string()), Some(credential.to_string()))
+            }
+            Some(None) => return Err("credentials must be username:credential".to_string()),
+            None => (None, None),
+        };
+
+        // IPv6 hosts are bracketed, e.g. stun:[2001:db8::1]:3478
+        let (host, port) = match host_port.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, port) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| format!("invalid host in {}", url))?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match host_port.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (host_port, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| format!("invalid port in {}", url))?,
+            None => DEFAULT_PORT,
+        };
+
+        if host.is_empty() {
+            return Err(format!("missing host in {}", url));
+        }
+
+        Ok(Self {
+            turn,
+            host: host.to_string(),
+            port,
+            username,
+            credential,
+        })
+    }
+}
+
+impl IceServer {
+    /// Parse the ice-server links out of a `Link` header value, as described
+    /// in section 4.6 of the WHIP spec
+    pub fn from_link_header(value: &str) -> Vec<Self> {
+        let mut servers = Vec::new();
+        for link in split_unquoted(value, ',') {
+            let mut params = split_unquoted(link, ';').into_iter();
+            let Some(url) = params
+                .next()
+                .and_then(|url| url.trim().strip_prefix('<'))
+                .and_then(|url| url.strip_suffix('>'))
+            else {
+                continue;
+            };
+
+            let mut rel = None;
+            let mut username = None;
+            let mut credential = None;
+            for param in params {
+                let Some((key, value)) = param.split_once('=') else {
+                    continue;
+                };
+                let value = value.trim().trim_matches('"').to_string();
+                match key.trim() {
+                    "rel" => rel = Some(value),
+                    "username" => username = Some(value),
+                    "credential" => credential = Some(value),
+                    _ => {}
+                }
+            }
+
+            if rel.as_deref() != Some("ice-server") {
+                continue;
+            }
+
+            match url.parse::<IceServer>() {
+                Ok(mut server) => {
+                    server.username = server.username.or(username);
+                    server.credential = server.credential.or(credential);
+                    servers.push(server);
+                }
+                Err(err) => warn!("ignoring ice-server {}: {}", url, err),
+            }
+        }
+
+        servers
+    }
+
+    /// Resolve the server's addresses, of either family
+    pub async fn resolve(&self) -> Result<Vec<SocketAddr>, WebrtcError> {
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((self.host.as_str(), self.port))
+            .await
+            .map_err(|e| WebrtcError::NetworkError(e.into()))?
+            .collect();
+        if addrs.is_empty() {
+            return Err(WebrtcError::NetworkError(
+                format!("{} has no addresses", self.host).into(),
+            ));
+        }
+
+        Ok(addrs)
+    }
+}
+
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&value[start..]);
+
+    parts
+}
+
+/// Ask a STUN server which address our packets arrive from
+pub async fn binding(socket: &UdpSocket, server: SocketAddr) -> Result<SocketAddr, WebrtcError> {
+    let response = transact(socket, server, &Message::new(BINDING_REQUEST), None).await?;
+    if response.kind != BINDING_REQUEST | SUCCESS_RESPONSE {
+        return Err(WebrtcError::ServerError(
+            format!("STUN binding failed: {:?}", response.error_code()).into(),
+        ));
+    }
+
+    response
+        .address(ATTR_XOR_MAPPED_ADDRESS)
+        .ok_or_else(|| WebrtcError::ServerError("STUN response has no mapped address".into()))
+}
+
+/// A relayed address allocated on a TURN server. Everything to and from the
+/// relay goes through `socket`, which is used for nothing else
+pub struct TurnAllocation {
+    pub socket: UdpSocket,
+    pub buf: [u8; 1500],
+    pub server: SocketAddr,
+    pub relayed: SocketAddr,
+    username: String,
+    realm: String,
+    nonce: String,
+    key: Vec<u8>,
+    permissions: HashMap<IpAddr, Instant>,
+    refresh_at: Instant,
+}
+
+impl TurnAllocation {
+    pub async fn allocate(
+        socket: UdpSocket,
+        server: SocketAddr,
+        username: &str,
+        credential: &str,
+    ) -> Result<Self, WebrtcError> {
+        let request = || {
+            Message::new(ALLOCATE_REQUEST)
+                .with(ATTR_REQUESTED_TRANSPORT, vec![UDP_TRANSPORT, 0, 0, 0])
+        };
+
+        // The first attempt is expected to be challenged for credentials
+        let challenge = transact(&socket, server, &request(), None).await?;
+        if challenge.error_code() != Some(401) {
+            return Err(WebrtcError::ServerError(
+                format!("TURN allocate not challenged: {:?}", challenge.error_code()).into(),
+            ));
+        }
+
+        let realm = challenge.string(ATTR_REALM).unwrap_or_default();
+        let mut allocation = Self {
+            socket,
+            buf: [0; 1500],
+            server,
+            relayed: server,
+            key: long_term_key(username, &realm, credential),
+            username: username.to_string(),
+            realm,
+            nonce: challenge.string(ATTR_NONCE).unwrap_or_default(),
+            permissions: HashMap::new(),
+            refresh_at: Instant::now(),
+        };
+
+        let mut response = allocation.authenticated_transact(request()).await?;
+        if response.error_code() == Some(438) {
+            allocation.update_nonce(&response);
+            response = allocation.authenticated_transact(request()).await?;
+        }
+
+        if response.kind != ALLOCATE_REQUEST | SUCCESS_RESPONSE {
+            return Err(WebrtcError::ServerError(
+                format!("TURN allocate failed: {:?}", response.error_code()).into(),
+            ));
+        }
+
+        allocation.relayed = response
+            .address(ATTR_XOR_RELAYED_ADDRESS)
+            .ok_or_else(|| WebrtcError::ServerError("TURN response has no relay".into()))?;
+        allocation.refresh_at = Instant::now() + response.lifetime() / 2;
+        info!("TURN relay {} allocated on {}", allocation.relayed, server);
+
+        Ok(allocation)
+    }
+
+    /// Datagrams to send to the server to relay `data` to `peer`, creating
+    /// or refreshing the permission for the peer first if needed
+    pub fn send(&mut self, peer: SocketAddr, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut datagrams = Vec::new();
+
+        let now = Instant::now();
+        let expired = self
+            .permissions
+            .get(&peer.ip())
+            .is_none_or(|created| now - *created > PERMISSION_REFRESH);
+        if expired {
+            debug!("TURN permission for {}", peer.ip());
+            self.permissions.insert(peer.ip(), now);
+            let request =
+                Message::new(CREATE_PERMISSION_REQUEST).with_address(ATTR_XOR_PEER_ADDRESS, peer);
+            let request = self.authenticate(request);
+            datagrams.push(request.encode(Some(&self.key)));
+        }
+
+        let indication = Message::new(SEND_INDICATION)
+            .with_address(ATTR_XOR_PEER_ADDRESS, peer)
+            .with(ATTR_DATA, data.to_vec());
+        datagrams.push(indication.encode(None));
+
+        datagrams
+    }
+
+    /// Unwrap peer data from a datagram the server sent, handling responses
+    /// to our own requests along the way
+    pub fn receive(&mut self, datagram: &[u8]) -> Option<(SocketAddr, Vec<u8>)> {
+        let message = Message::decode(datagram)?;
+        match message.kind {
+            DATA_INDICATION => {
+                let peer = message.address(ATTR_XOR_PEER_ADDRESS)?;
+                let data = message.get(ATTR_DATA)?.to_vec();
+                Some((peer, data))
+            }
+            kind if kind & ERROR_RESPONSE == ERROR_RESPONSE => {
+                warn!("TURN request failed: {:?}", message.error_code());
+                if message.error_code() == Some(438) {
+                    // Redo everything with the fresh nonce
+                    self.update_nonce(&message);
+                    self.permissions.clear();
+                    self.refresh_at = Instant::now();
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// A Refresh request once the allocation is halfway through its lifetime
+    pub fn poll_refresh(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if now < self.refresh_at {
+            return None;
+        }
+
+        // Refreshed for the default lifetime, retried a minute later if lost
+        self.refresh_at = now + Duration::from_secs(60);
+        let request = self.authenticate(Message::new(REFRESH_REQUEST));
+        Some(request.encode(Some(&self.key)))
+    }
+
+    /// A Refresh request with a zero lifetime, which frees the allocation
+    pub fn deallocate(&self) -> Vec<u8> {
+        let request = Message::new(REFRESH_REQUEST).with(ATTR_LIFETIME, vec![0; 4]);
+        self.authenticate(request).encode(Some(&self.key))
+    }
+
+    fn authenticate(&self, message: Message) -> Message {
+        message
+            .with(ATTR_USERNAME, self.username.as_bytes().to_vec())
+            .with(ATTR_REALM, self.realm.as_bytes().to_vec())
+            .with(ATTR_NONCE, self.nonce.as_bytes().to_vec())
+    }
+
+    async fn authenticated_transact(&self, request: Message) -> Result<Message, WebrtcError> {
+        let request = self.authenticate(request);
+        transact(&self.socket, self.server, &request, Some(&self.key)).await
+    }
+
+    fn update_nonce(&mut self, message: &Message) {
+        if let Some(nonce) = message.string(ATTR_NONCE) {
+            self.nonce = nonce;
+        }
+    }
+}
+
+/// Send a request and wait for its response, retransmitting on loss
+async fn transact(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    request: &Message,
+    key: Option<&[u8]>,
+) -> Result<Message, WebrtcError> {
+    let datagram = request.encode(key);
+    let mut buf = [0; 1500];
+
+    for _ in 0..RETRANSMITS {
+        socket
+            .send_to(&datagram, server)
+            .await
+            .map_err(|e| WebrtcError::NetworkError(e.into()))?;
+
+        let deadline = tokio::time::Instant::now() + RETRANSMIT_TIMEOUT;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await
+        {
+            let (n, source) = received.map_err(|e| WebrtcError::NetworkError(e.into()))?;
+            match Message::decode(&buf[..n]) {
+                Some(response)
+                    if source == server && response.transaction_id == request.transaction_id =>
+                {
+                    return Ok(response);
+                }
+                // Anything else is dropped, ICE retransmits its own checks
+                _ => debug!("dropping {} bytes from {} while gathering", n, source),
+            }
+        }
+    }
+
+    Err(WebrtcError::NetworkError(
+        format!("no response from {}", server).into(),
+    ))
+}
+
+fn long_term_key(username: &str, realm: &str, credential: &str) -> Vec<u8> {
+    let input = format!("{}:{}:{}", username, realm, credential);
+    hash(MessageDigest::md5(), input.as_bytes())
+        .expect("md5 available")
+        .to_vec()
+}
+
+struct Message {
+    kind: u16,
+    transaction_id: [u8; 12],
+    attributes: Vec<(u16, Vec<u8>)>,
+}
+
+impl Message {
+    fn new(kind: u16) -> Self {
+        Self {
+            kind,
+            transaction_id: rand::random(),
+            attributes: Vec::new(),
+        }
+    }
+
+    fn with(mut self, attribute: u16, value: Vec<u8>) -> Self {
+        self.attributes
+            .retain(|(existing, _)| *existing != attribute);
+        self.attributes.push((attribute, value));
+        self
+    }
+
+    fn with_address(self, attribute: u16, addr: SocketAddr) -> Self {
+        let value = xor_address(addr, &self.transaction_id);
+        self.with(attribute, value)
+    }
+
+    fn get(&self, attribute: u16) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|(existing, _)| *existing == attribute)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn string(&self, attribute: u16) -> Option<String> {
+        self.get(attribute)
+            .map(|value| String::from_utf8_lossy(value).into_owned())
+    }
+
+    fn address(&self, attribute: u16) -> Option<SocketAddr> {
+        let value = self.get(attribute)?;
+        if value.len() < 4 {
+            return None;
+        }
+
+        let port = u16::from_be_bytes([value[2], value[3]]) ^ (MAGIC_COOKIE >> 16) as u16;
+        let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+        mask.extend_from_slice(&self.transaction_id);
+
+        let ip = match value[1] {
+            0x01 if value.len() >= 8 => {
+                let mut octets = [0; 4];
+                for (i, octet) in octets.iter_mut().enumerate() {
+                    *octet = value[4 + i] ^ mask[i];
+                }
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            0x02 if value.len() >= 20 => {
+                let mut octets = [0; 16];
+                for (i, octet) in octets.iter_mut().enumerate() {
+                    *octet = value[4 + i] ^ mask[i];
+                }
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+
+        Some(SocketAddr::new(ip, port))
+    }
+
+    fn error_code(&self) -> Option<u16> {
+        let value = self.get(ATTR_ERROR_CODE)?;
+        if value.len() < 4 {
+            return None;
+        }
+
+        Some(value[2] as u16 * 100 + value[3] as u16)
+    }
+
+    fn lifetime(&self) -> Duration {
+        match self.get(ATTR_LIFETIME) {
+            Some(&[a, b, c, d]) => Duration::from_secs(u32::from_be_bytes([a, b, c, d]) as u64),
+            _ => Duration::from_secs(600),
+        }
+    }
+
+    fn encode(&self, key: Option<&[u8]>) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(128);
+        buf.extend_from_slice(&self.kind.to_be_bytes());
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(&self.transaction_id);
+
+        for (attribute, value) in &self.attributes {
+            buf.extend_from_slice(&attribute.to_be_bytes());
+            buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            buf.extend_from_slice(value);
+            buf.resize(buf.len().next_multiple_of(4), 0);
+        }
+
+        // MESSAGE-INTEGRITY covers the header with a length that already
+        // includes the integrity attribute itself
+        if let Some(key) = key {
+            let length = (buf.len() - 20 + 24) as u16;
+            buf[2..4].copy_from_slice(&length.to_be_bytes());
+
+            let key = PKey::hmac(key).expect("hmac key");
+            let mut signer = Signer::new(MessageDigest::sha1(), &key).expect("hmac-sha1");
+            signer.update(&buf).expect("hmac-sha1");
+            let integrity = signer.sign_to_vec().expect("hmac-sha1");
+
+            buf.extend_from_slice(&ATTR_MESSAGE_INTEGRITY.to_be_bytes());
+            buf.extend_from_slice(&(integrity.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&integrity);
+        }
+
+        let length = (buf.len() - 20) as u16;
+        buf[2..4].copy_from_slice(&length.to_be_bytes());
+
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 20 || buf[0] & 0xc0 != 0 || buf[4..8] != MAGIC_COOKIE.to_be_bytes() {
+            return None;
+        }
+
+        let kind = u16::from_be_bytes([buf[0], buf[1]]);
+        let length = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        let body = buf.get(20..20 + length)?;
+
+        let mut attributes = Vec::new();
+        let mut offset = 0;
+        while offset + 4 <= body.len() {
+            let attribute = u16::from_be_bytes([body[offset], body[offset + 1]]);
+            let len = u16::from_be_bytes([body[offset + 2], body[offset + 3]]) as usize;
+            let value = body.get(offset + 4..offset + 4 + len)?;
+            attributes.push((attribute, value.to_vec()));
+            offset = (offset + 4 + len).next_multiple_of(4);
+        }
+
+        Some(Self {
+            kind,
+            transaction_id: buf[8..20].try_into().ok()?,
+            attributes,
+        })
+    }
+}
+
+fn xor_address(addr: SocketAddr, transaction_id: &[u8; 12]) -> Vec<u8> {
+    let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+    mask.extend_from_slice(transaction_id);
+
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let (family, octets) = match addr.ip() {
+        IpAddr::V4(ip) => (0x01, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (0x02, ip.octets().to_vec()),
+    };
+
+    let mut value = vec![0, family];
+    value.extend_from_slice(&port.to_be_bytes());
+    value.extend(octets.iter().zip(&mask).map(|(octet, mask)| octet ^ mask));
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_stun_url() {
+        let server: IceServer = "stun:stun.l.google.com:19302".parse().unwrap();
+        assert!(!server.turn);
+        assert_eq!(server.host, "stun.l.google.com");
+        assert_eq!(server.port, 19302);
+        assert_eq!(server.username, None);
+
+        let server: IceServer = "stun:[2001:db8::1]".parse().unwrap();
+        assert_eq!(server.host, "2001:db8::1");
+        assert_eq!(server.port, DEFAULT_PORT);
+    }
+
+    #[test]
+    fn parse_turn_url() {
+        let server: IceServer = "turn:user:pass@turn.example.com?transport=udp"
+            .parse()
+            .unwrap();
+        assert!(server.turn);
+        assert_eq!(server.host, "turn.example.com");
+        assert_eq!(server.port, DEFAULT_PORT);
+        assert_eq!(server.username.as_deref(), Some("user"));
+        assert_eq!(server.credential.as_deref(), Some("pass"));
+
+        let server: IceServer = "turn:[2001:db8::1]:5349".parse().unwrap();
+        assert_eq!(server.host, "2001:db8::1");
+        assert_eq!(server.port, 5349);
+    }
+
+    #[test]
+    fn reject_unsupported_urls() {
+        for url in [
+            "turns:turn.example.com",
+            "turn:turn.example.com?transport=tcp",
+            "http://example.com",
+            "stun:",
+            "stun:example.com:port",
+            "turn:user@turn.example.com",
+        ] {
+            assert!(url.parse::<IceServer>().is_err(), "{} parsed", url);
+        }
+    }
+
+    #[test]
+    fn parse_link_header() {
+        let servers = IceServer::from_link_header(
+            "<stun:stun.example.net>; rel=\"ice-server\", \
+             <turn:turn.example.net?transport=udp>; rel=\"ice-server\"; \
+             username=\"user\"; credential=\"my;secret,password\"; credential-type=\"password\", \
+             <https://example.net/docs>; rel=\"help\"",
+        );
+
+        assert_eq!(servers.len(), 2);
+        assert!(!servers[0].turn);
+        assert_eq!(servers[0].host, "stun.example.net");
+        assert!(servers[1].turn);
+        assert_eq!(servers[1].host, "turn.example.net");
+        assert_eq!(servers[1].username.as_deref(), Some("user"));
+        assert_eq!(servers[1].credential.as_deref(), Some("my;secret,password"));
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let v4: SocketAddr = "192.0.2.1:32853".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+            .parse()
+            .unwrap();
+        let message = Message::new(ALLOCATE_REQUEST | SUCCESS_RESPONSE)
+            .with_address(ATTR_XOR_RELAYED_ADDRESS, v4)
+            .with_address(ATTR_XOR_MAPPED_ADDRESS, v6)
+            .with(ATTR_LIFETIME, 1200u32.to_be_bytes().to_vec())
+            .with(ATTR_NONCE, b"odd-length".to_vec());
+
+        let encoded = message.encode(None);
+        assert_eq!(encoded.len() % 4, 0);
+        assert_eq!(
+            u16::from_be_bytes([encoded[2], encoded[3]]) as usize,
+            encoded.len() - 20
+        );
+
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(decoded.kind, ALLOCATE_REQUEST | SUCCESS_RESPONSE);
+        assert_eq!(decoded.transaction_id, message.transaction_id);
+        assert_eq!(decoded.address(ATTR_XOR_RELAYED_ADDRESS), Some(v4));
+        assert_eq!(decoded.address(ATTR_XOR_MAPPED_ADDRESS), Some(v6));
+        assert_eq!(decoded.lifetime(), Duration::from_secs(1200));
+        assert_eq!(decoded.string(ATTR_NONCE).as_deref(), Some("odd-length"));
+    }
+
+    #[test]
+    fn message_integrity() {
+        let key = long_term_key("user", "realm", "pass");
+        let encoded = Message::new(REFRESH_REQUEST)
+            .with(ATTR_USERNAME, b"user".to_vec())
+            .encode(Some(&key));
+
+        // Last attribute, covering everything before it with the final length
+        let (signed, integrity) = encoded.split_at(encoded.len() - 24);
+        assert_eq!(
+            u16::from_be_bytes([integrity[0], integrity[1]]),
+            ATTR_MESSAGE_INTEGRITY
+        );
+        assert_eq!(
+            u16::from_be_bytes([signed[2], signed[3]]) as usize,
+            encoded.len() - 20
+        );
+
+        let key = PKey::hmac(&key).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha1(), &key).unwrap();
+        signer.update(signed).unwrap();
+        assert_eq!(signer.sign_to_vec().unwrap(), &integrity[4..]);
+    }
+
+    #[test]
+    fn decode_error_code() {
+        let message = Message::new(ALLOCATE_REQUEST | ERROR_RESPONSE)
+            .with(ATTR_ERROR_CODE, vec![0, 0, 4, 38]);
+        let decoded = Message::decode(&message.encode(None)).unwrap();
+        assert_eq!(decoded.error_code(), Some(438));
+    }
+
+    #[test]
+    fn decode_rejects_garbage() {
+        assert!(Message::decode(&[0; 19]).is_none());
+        // RTP has the top bits of the first byte set
+        let mut rtp = Message::new(BINDING_REQUEST).encode(None);
+        rtp[0] = 0x80;
+        assert!(Message::decode(&rtp).is_none());
+        // Length running past the end of the datagram
+        let mut truncated = Message::new(BINDING_REQUEST)
+            .with(ATTR_DATA, vec![1; 8])
+            .encode(None);
+        truncated.truncate(24);
+        assert!(Message::decode(&truncated).is_none());
+    }
+
+    #[tokio::test]
+    async fn binding_returns_mapped_address() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            let (n, source) = server.recv_from(&mut buf).await.unwrap();
+            let request = Message::decode(&buf[..n]).unwrap();
+            let response = Message {
+                kind: BINDING_REQUEST | SUCCESS_RESPONSE,
+                transaction_id: request.transaction_id,
+                attributes: Vec::new(),
+            }
+            .with_address(ATTR_XOR_MAPPED_ADDRESS, source);
+            server
+                .send_to(&response.encode(None), source)
+                .await
+                .unwrap();
+        });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mapped = binding(&socket, server_addr).await.unwrap();
+        assert_eq!(mapped, socket.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn turn_send_and_receive() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = socket.local_addr().unwrap();
+        let mut allocation = TurnAllocation {
+            socket,
+            buf: [0; 1500],
+            server,
+            relayed: "198.51.100.1:49152".parse().unwrap(),
+            username: "user".to_string(),
+            realm: "realm".to_string(),
+            nonce: "nonce".to_string(),
+            key: long_term_key("user", "realm", "pass"),
+            permissions: HashMap::new(),
+            refresh_at: Instant::now(),
+        };
+        let peer: SocketAddr = "203.0.113.7:5000".parse().unwrap();
+
+        // The first datagram to a peer is preceded by a permission for it
+        let datagrams = allocation.send(peer, b"hello");
+        assert_eq!(datagrams.len(), 2);
+        let permission = Message::decode(&datagrams[0]).unwrap();
+        assert_eq!(permission.kind, CREATE_PERMISSION_REQUEST);
+        assert_eq!(permission.address(ATTR_XOR_PEER_ADDRESS), Some(peer));
+        let indication = Message::decode(&datagrams[1]).unwrap();
+        assert_eq!(indication.kind, SEND_INDICATION);
+        assert_eq!(indication.get(ATTR_DATA), Some(&b"hello"[..]));
+
+        assert_eq!(allocation.send(peer, b"again").len(), 1);
+
+        let data = Message::new(DATA_INDICATION)
+            .with_address(ATTR_XOR_PEER_ADDRESS, peer)
+            .with(ATTR_DATA, b"world".to_vec())
+            .encode(None);
+        assert_eq!(allocation.receive(&data), Some((peer, b"world".to_vec())));
+
+        // A stale nonce drops the permissions so they are made again
+        let stale = Message::new(CREATE_PERMISSION_REQUEST | ERROR_RESPONSE)
+            .with(ATTR_ERROR_CODE, vec![0, 0, 4, 38])
+            .with(ATTR_NONCE, b"fresh".to_vec())
+            .encode(None);
+        assert_eq!(allocation.receive(&stale), None);
+        assert_eq!(allocation.nonce, "fresh");
+        assert_eq!(allocation.send(peer, b"retry").len(), 2);
+    }
+}
@@ -0,0 +1,163 @@
+//! The HTTP or HTTPS listener the WHIP and WHEP servers are reached on
+
+use anyhow::{Context, Result};
+use axum::{
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, ETAG, IF_MATCH, LINK, LOCATION},
+        HeaderName, HeaderValue, Method,
+    },
+    response::Response,
+    Router,
+};
+use axum_server::tls_openssl::{OpenSSLAcceptor, OpenSSLConfig};
+use openssl::{
+    asn1::Asn1Time,
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::PKey,
+    x509::{extension::SubjectAlternativeName, X509NameBuilder, X509},
+};
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::info;
+
+const ACCEPT_POST: &str = "accept-post";
+
+pub struct Listener {
+    listener: TcpListener,
+    tls: Option<OpenSSLConfig>,
+    cors: CorsLayer,
+}
+
+impl Listener {
+    /// Bind right away so a taken port is reported before anything else starts
+    pub fn bind(addr: SocketAddr, tls: Option<OpenSSLConfig>, cors: CorsLayer) -> Result<Self> {
+        let listener =
+            TcpListener::bind(addr).with_context(|| format!("Failed to listen on {}", addr))?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            listener,
+            tls,
+            cors,
+        })
+    }
+
+    /// Base URL of the server, for telling the user where to point clients
+    pub fn url(&self) -> String {
+        let scheme = match self.tls {
+            Some(_) => "https",
+            None => "http",
+        };
+
+        match self.listener.local_addr() {
+            Ok(addr) => format!("{}://{}", scheme, addr),
+            Err(_) => format!("{}://", scheme),
+        }
+    }
+
+    pub async fn serve(self, app: Router) -> Result<()> {
+        let app = app.layer(self.cors).into_make_service();
+        match self.tls {
+            Some(tls) => {
+                axum_server::from_tcp(self.listener)
+                    .acceptor(OpenSSLAcceptor::new(tls))
+                    .serve(app)
+                    .await?
+            }
+            None => axum_server::from_tcp(self.listener).serve(app).await?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Let browsers on `origins`, or any origin if none are given, make requests
+/// and read the headers WHIP and WHEP responses carry
+pub fn cors(origins: &[String]) -> Result<CorsLayer> {
+    let allow_origin = if origins.is_empty() {
+        AllowOrigin::any()
+    } else {
+        let origins = origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin)
+                    .with_context(|| format!("Invalid allowed origin {}", origin))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        AllowOrigin::list(origins)
+    };
+
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::POST, Method::PATCH, Method::DELETE, Method::OPTIONS])
+        .allow_headers([AUTHORIZATION, CONTENT_TYPE, IF_MATCH])
+        .expose_headers([LOCATION, LINK, ETAG, HeaderName::from_static(ACCEPT_POST)]))
+}
+
+/// Answer a plain OPTIONS on a WHIP or WHEP endpoint, preflights are handled
+/// by the CORS layer before they get here
+pub async fn options() -> Response<String> {
+    Response::builder()
+        .status(204)
+        .header(ACCEPT_POST, "application/sdp")
+        .body(String::new())
+        .unwrap()
+}
+
+/// Load a PEM certificate chain and private key
+pub fn load_tls(cert: &str, key: &str) -> Result<OpenSSLConfig> {
+    OpenSSLConfig::from_pem_file(cert, key)
+        .with_context(|| format!("Failed to load certificate {} with key {}", cert, key))
+}
+
+/// Generate a certificate for localhost and the addresses of this machine,
+/// valid for a year. Clients have to be told to trust it
+pub fn self_signed_tls() -> Result<OpenSSLConfig> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, "bitwhip")?;
+    let name = name.build();
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&*BigNum::from_u32(rand::random())?.to_asn1_integer()?)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(&*Asn1Time::days_from_now(0)?)?;
+    builder.set_not_after(&*Asn1Time::days_from_now(365)?)?;
+
+    let mut names = SubjectAlternativeName::new();
+    names.dns("localhost");
+    let mut ips: Vec<IpAddr> = local_ip_address::list_afinet_netifas()
+        .map(|interfaces| interfaces.into_iter().map(|(_, ip)| ip).collect())
+        .unwrap_or_default();
+    ips.sort();
+    ips.dedup();
+    for ip in ips {
+        names.ip(&ip.to_string());
+    }
+    let names = names.build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(names)?;
+
+    builder.sign(&key, MessageDigest::sha256())?;
+    let cert = builder.build();
+
+    let fingerprint = cert
+        .digest(MessageDigest::sha256())?
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":");
+    info!("Generated self-signed certificate, SHA-256 {}", fingerprint);
+
+    Ok(OpenSSLConfig::from_pem(
+        &cert.to_pem()?,
+        &key.private_key_to_pem_pkcs8()?,
+    )?)
+}
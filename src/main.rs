@@ -1,51 +1,173 @@
-use crate::player::render_video;
-use anyhow::{Error, Result};
-use axum::{response::Response, routing::post, Router};
-use clap::{Parser, Subcommand};
-use encoder::Encoder;
+use crate::player::{render_streams, render_video, PlayerFrame};
+use anyhow::{anyhow, bail, Error, Result};
+use auth::Auth;
+use axum::{
+    extract::Path,
+    http::{
+        header::{ACCEPT, CONTENT_TYPE, ETAG, IF_MATCH, WWW_AUTHENTICATE},
+        HeaderMap, Uri,
+    },
+    response::Response,
+    routing::{delete, post},
+    Router,
+};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use client::WebrtcError;
+use encoder::{create_hw_frames, AudioEncoder, Encoder, Scaler};
 use ffmpeg_next::{
-    ffi::{av_buffer_ref, AVBufferRef},
+    ffi::{av_buffer_ref, AVBufferRef, AVHWDeviceType},
     format::Pixel,
     Packet, Rational,
 };
-use log::LevelFilter;
+use ice::IceServer;
+use log::{info, LevelFilter};
 use simplelog::{ColorChoice, Config, TermLogger, TerminalMode};
-use source::Source;
-use std::{collections::HashMap, sync::mpsc, time::Instant};
+use source::{AudioSource, Source};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
+};
+use str0m::media::MediaKind;
 
+mod auth;
 mod client;
 mod encoder;
+mod ice;
+mod listen;
 mod player;
 mod source;
+mod tcp;
 mod whip;
 
-struct EncodedPacket(Packet, Instant);
+#[derive(Clone)]
+struct EncodedPacket {
+    kind: MediaKind,
+    packet: Packet,
+    // Presentation time relative to when the stream started
+    pts: Duration,
+    // Simulcast layer the packet belongs to
+    rid: Option<&'static str>,
+}
 
 #[no_mangle]
 pub static NvOptimusEnablement: i32 = 1;
 #[no_mangle]
 pub static AmdPowerXpressRequestHighPerformance: i32 = 1;
 
-fn create_encoder(width: u32, height: u32, hw_frames: *mut AVBufferRef) -> Result<Encoder> {
+fn create_encoder(
+    kind: EncoderKind,
+    width: u32,
+    height: u32,
+    framerate: u32,
+    bit_rate: usize,
+    hw_frames: *mut AVBufferRef,
+) -> Result<(EncoderKind, Encoder)> {
+    if kind != EncoderKind::Auto {
+        return Ok((
+            kind,
+            open_encoder(kind, width, height, framerate, bit_rate, hw_frames)?,
+        ));
+    }
+
+    for kind in EncoderKind::PRIORITY {
+        match open_encoder(kind, width, height, framerate, bit_rate, hw_frames) {
+            Ok(encoder) => {
+                info!("Using {:?} encoder", kind);
+                return Ok((kind, encoder));
+            }
+            Err(err) => info!("{:?} encoder unavailable: {}", kind, err),
+        }
+    }
+
+    bail!("No usable H264 encoder found")
+}
+
+fn open_encoder(
+    kind: EncoderKind,
+    width: u32,
+    height: u32,
+    framerate: u32,
+    bit_rate: usize,
+    hw_frames: *mut AVBufferRef,
+) -> Result<Encoder> {
+    // Baseline profiles keep the bitstream decodable as the constrained
+    // baseline (42e01f) profile that Client::send_video negotiates
+    let (name, options) = match kind {
+        EncoderKind::Nvenc => (
+            "h264_nvenc",
+            vec![("preset", "p6"), ("tune", "ull"), ("forced-idr", "1")],
+        ),
+        EncoderKind::Qsv => (
+            "h264_qsv",
+            vec![
+                ("preset", "veryfast"),
+                ("async_depth", "1"),
+                ("forced_idr", "1"),
+            ],
+        ),
+        EncoderKind::Vaapi => ("h264_vaapi", vec![("profile", "constrained_baseline")]),
+        EncoderKind::Amf => (
+            "h264_amf",
+            vec![("usage", "ultralowlatency"), ("quality", "speed")],
+        ),
+        EncoderKind::X264 => (
+            "libx264",
+            vec![
+                ("preset", "ultrafast"),
+                ("tune", "zerolatency"),
+                ("profile", "baseline"),
+                ("forced-idr", "1"),
+            ],
+        ),
+        EncoderKind::Openh264 => ("libopenh264", vec![("profile", "constrained_baseline")]),
+        EncoderKind::Auto => bail!("auto is not a concrete encoder"),
+    };
+
     let encoder = Encoder::new(
-        "h264_nvenc",
-        Some(HashMap::from([
-            ("preset".into(), "p6".into()),
-            ("tune".into(), "ull".into()),
-        ])),
+        name,
+        Some(
+            options
+                .into_iter()
+                .map(|(key, value)| (key.into(), value.into()))
+                .collect(),
+        ),
         |encoder| {
-            let frame_rate = Rational::new(60, 1);
-            encoder.set_bit_rate(5000 * 1000);
+            let frame_rate = Rational::new(framerate as i32, 1);
+            encoder.set_bit_rate(bit_rate);
             encoder.set_width(width);
             encoder.set_height(height);
             encoder.set_time_base(frame_rate.invert());
             encoder.set_frame_rate(Some(frame_rate));
             encoder.set_gop(120);
             encoder.set_max_b_frames(0);
-            encoder.set_format(Pixel::D3D11);
-            unsafe {
-                let encoder = &mut *encoder.as_mut_ptr();
-                encoder.hw_frames_ctx = av_buffer_ref(hw_frames);
+
+            match kind {
+                // NVENC and AMF can encode the source's D3D11 textures directly
+                EncoderKind::Nvenc | EncoderKind::Amf if !hw_frames.is_null() => {
+                    encoder.set_format(Pixel::D3D11);
+                    unsafe {
+                        let encoder = &mut *encoder.as_mut_ptr();
+                        encoder.hw_frames_ctx = av_buffer_ref(hw_frames);
+                    }
+                }
+                EncoderKind::Vaapi => {
+                    encoder.set_format(Pixel::VAAPI);
+                    let vaapi_frames = create_hw_frames(
+                        AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI,
+                        Pixel::VAAPI,
+                        Pixel::NV12,
+                        width,
+                        height,
+                    )?;
+                    unsafe {
+                        let encoder = &mut *encoder.as_mut_ptr();
+                        encoder.hw_frames_ctx = vaapi_frames;
+                    }
+                }
+                EncoderKind::X264 | EncoderKind::Openh264 => encoder.set_format(Pixel::YUV420P),
+                _ => encoder.set_format(Pixel::NV12),
             }
 
             Ok(())
@@ -55,6 +177,85 @@ fn create_encoder(width: u32, height: u32, hw_frames: *mut AVBufferRef) -> Resul
     Ok(encoder)
 }
 
+fn create_source(capture: &CaptureArgs) -> Result<Box<dyn Source + Send>> {
+    let pattern = match capture.source {
+        SourceKind::Desktop => return create_desktop_source(capture),
+        SourceKind::Testsrc => source::testsrc::Pattern::TestSrc,
+        SourceKind::Smptebars => source::testsrc::Pattern::SmpteBars,
+    };
+
+    Ok(Box::new(source::testsrc::TestPattern::new(
+        pattern,
+        capture.video_size.as_deref().unwrap_or("1280x720"),
+        capture.framerate,
+    )?))
+}
+
+#[cfg(target_os = "windows")]
+fn create_desktop_source(capture: &CaptureArgs) -> Result<Box<dyn Source + Send>> {
+    Ok(Box::new(source::dxdup::DisplayDuplicator::new(
+        capture.framerate,
+    )?))
+}
+
+#[cfg(target_os = "linux")]
+fn create_desktop_source(capture: &CaptureArgs) -> Result<Box<dyn Source + Send>> {
+    let display = match &capture.display {
+        Some(display) => display.clone(),
+        None => std::env::var("DISPLAY").unwrap_or_else(|_| ":0.0".into()),
+    };
+
+    Ok(Box::new(source::x11grab::X11Grab::new(
+        &display,
+        (capture.offset_x, capture.offset_y),
+        capture.video_size.as_deref(),
+        capture.framerate,
+    )?))
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+fn create_desktop_source(_capture: &CaptureArgs) -> Result<Box<dyn Source + Send>> {
+    bail!("Desktop capture is not supported on this platform")
+}
+
+#[cfg(target_os = "windows")]
+const DEFAULT_AUDIO_FORMAT: &str = "dshow";
+#[cfg(target_os = "macos")]
+const DEFAULT_AUDIO_FORMAT: &str = "avfoundation";
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+const DEFAULT_AUDIO_FORMAT: &str = "pulse";
+
+fn create_audio_source(
+    audio: &AudioArgs,
+    capture: &CaptureArgs,
+) -> Result<Option<Box<dyn AudioSource + Send>>> {
+    let source: Box<dyn AudioSource + Send> = match audio.audio {
+        AudioKind::None => return Ok(None),
+        AudioKind::Tone => Box::new(source::audio::Tone::new()?),
+        AudioKind::Input => {
+            let input = capture
+                .input
+                .as_deref()
+                .ok_or_else(|| anyhow!("--audio input requires --input"))?;
+            Box::new(source::audio::AudioInput::file(input, capture.looping)?)
+        }
+        AudioKind::Device => {
+            let device = match (&audio.audio_device, audio.audio_format.as_str()) {
+                (Some(device), _) => device.as_str(),
+                (None, "avfoundation") => ":0",
+                (None, "dshow") => bail!("--audio-device is required with dshow"),
+                (None, _) => "default",
+            };
+            Box::new(source::audio::AudioInput::device(
+                &audio.audio_format,
+                device,
+            )?)
+        }
+    };
+
+    Ok(Some(source))
+}
+
 #[derive(Parser)]
 #[command(name = "bitwhip")]
 #[command(bin_name = "bitwhip")]
@@ -67,6 +268,170 @@ struct Cli {
     verbose: u8,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum EncoderKind {
+    /// Probe hardware encoders first and fall back to software ones
+    Auto,
+    /// NVIDIA NVENC
+    Nvenc,
+    /// Intel Quick Sync Video
+    Qsv,
+    /// VA-API (Linux)
+    Vaapi,
+    /// AMD AMF
+    Amf,
+    /// x264 software encoder
+    X264,
+    /// Cisco OpenH264 software encoder
+    Openh264,
+}
+
+impl EncoderKind {
+    /// Order `auto` probes encoders in
+    const PRIORITY: [EncoderKind; 6] = [
+        EncoderKind::Nvenc,
+        EncoderKind::Qsv,
+        EncoderKind::Vaapi,
+        EncoderKind::Amf,
+        EncoderKind::X264,
+        EncoderKind::Openh264,
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SourceKind {
+    /// Capture the local desktop
+    Desktop,
+    /// FFmpeg testsrc2 pattern with a burned in frame counter and clock
+    Testsrc,
+    /// SMPTE color bars with a burned in frame counter and clock
+    Smptebars,
+}
+
+#[derive(Debug, Args)]
+struct CaptureArgs {
+    /// Where video frames come from
+    #[arg(long, value_enum, default_value_t = SourceKind::Desktop)]
+    source: SourceKind,
+
+    /// The X11 display to capture, defaults to $DISPLAY (Linux only)
+    #[arg(long)]
+    display: Option<String>,
+
+    /// Horizontal offset of the captured region (Linux only)
+    #[arg(long, default_value_t = 0)]
+    offset_x: u32,
+
+    /// Vertical offset of the captured region (Linux only)
+    #[arg(long, default_value_t = 0)]
+    offset_y: u32,
+
+    /// Size of the captured region (Linux only) or test pattern as WIDTHxHEIGHT
+    #[arg(long)]
+    video_size: Option<String>,
+
+    /// Capture framerate
+    #[arg(long, default_value_t = 60)]
+    framerate: u32,
+
+    /// Publish a media file or URL instead of capturing
+    #[arg(long)]
+    input: Option<String>,
+
+    /// Start --input over from the beginning when it ends
+    #[arg(long = "loop")]
+    looping: bool,
+
+    /// Re-encode --input even when its H264 could be sent as is
+    #[arg(long)]
+    reencode: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum AudioKind {
+    /// Publish video only
+    None,
+    /// Capture from an audio input device
+    Device,
+    /// A 1kHz tone that beeps once a second
+    Tone,
+    /// The audio track of --input
+    Input,
+}
+
+#[derive(Debug, Args)]
+struct AudioArgs {
+    /// Where audio comes from
+    #[arg(long, value_enum, default_value_t = AudioKind::None)]
+    audio: AudioKind,
+
+    /// The FFmpeg input device to capture audio with, e.g. pulse, alsa, dshow or avfoundation
+    #[arg(long, default_value = DEFAULT_AUDIO_FORMAT)]
+    audio_format: String,
+
+    /// The audio device to capture, e.g. "default" for pulse or "audio=Microphone" for dshow
+    #[arg(long)]
+    audio_device: Option<String>,
+
+    /// Packet loss in percent to protect audio against with Opus in-band FEC, 0 turns it off
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+    opus_fec: u8,
+}
+
+#[derive(Debug, Args)]
+struct BitrateArgs {
+    /// Video bitrate in kbps to start at, it then follows the bandwidth estimate
+    #[arg(long, default_value_t = 5000)]
+    bitrate: u64,
+
+    /// Lowest video bitrate in kbps
+    #[arg(long, default_value_t = 500)]
+    min_bitrate: u64,
+
+    /// Highest video bitrate in kbps
+    #[arg(long, default_value_t = 8000)]
+    max_bitrate: u64,
+}
+
+#[derive(Debug, Args)]
+struct ListenArgs {
+    /// Address and port the server listens on
+    #[arg(long, default_value = "0.0.0.0:1337")]
+    bind: SocketAddr,
+
+    /// PEM certificate chain to serve HTTPS with
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<String>,
+
+    /// PEM private key of --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<String>,
+
+    /// Serve HTTPS with a self-signed certificate generated at startup
+    #[arg(long, conflicts_with = "tls_cert")]
+    self_signed: bool,
+
+    /// An origin browsers may make requests from, can be given more than
+    /// once. Any origin is allowed if none are given
+    #[arg(long = "allow-origin")]
+    allow_origins: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+struct AuthArgs {
+    /// A bearer token publishers may use, can be given more than once
+    #[arg(long = "token")]
+    tokens: Vec<String>,
+
+    /// Accept JWTs signed with HS256 using this secret
+    #[arg(long)]
+    jwt_secret: Option<String>,
+
+    /// Accept JWTs signed with RS256 by the key in this PEM file
+    #[arg(long)]
+    jwt_public_key: Option<String>,
+}
+
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Stream to a WHIP destination
@@ -77,10 +442,64 @@ enum Commands {
 
         /// The WHIP bearer token
         token: Option<String>,
+
+        /// The H264 encoder to use
+        #[arg(long, value_enum, default_value_t = EncoderKind::Auto)]
+        encoder: EncoderKind,
+
+        #[command(flatten)]
+        capture: CaptureArgs,
+
+        #[command(flatten)]
+        audio: AudioArgs,
+
+        #[command(flatten)]
+        bitrate: BitrateArgs,
+
+        /// STUN or TURN server to gather candidates from, e.g. stun:stun.l.google.com:19302
+        /// or turn:username:credential@turn.example.com:3478
+        #[arg(long = "ice-server")]
+        ice_servers: Vec<IceServer>,
+
+        /// Publish full, half and quarter resolution layers for the server to pick from
+        #[arg(long)]
+        simulcast: bool,
+    },
+
+    /// Capture and encode once, serving the stream to any number of WHEP viewers
+    Serve {
+        /// The H264 encoder to use
+        #[arg(long, value_enum, default_value_t = EncoderKind::Auto)]
+        encoder: EncoderKind,
+
+        #[command(flatten)]
+        capture: CaptureArgs,
+
+        #[command(flatten)]
+        audio: AudioArgs,
+
+        #[command(flatten)]
+        listen: ListenArgs,
+    },
+
+    /// Relay WHIP publishers to WHEP viewers without decoding
+    Relay {
+        #[command(flatten)]
+        listen: ListenArgs,
     },
 
     /// Start a WHIP server that accepts incoming requests
-    PlayWHIP {},
+    PlayWHIP {
+        /// How many publishers can be played at once, each gets its own tile
+        #[arg(long, default_value_t = 4)]
+        max_publishers: usize,
+
+        #[command(flatten)]
+        auth: AuthArgs,
+
+        #[command(flatten)]
+        listen: ListenArgs,
+    },
 
     /// Play from a WHEP destination
     #[command(arg_required_else_help = true)]
@@ -90,6 +509,10 @@ enum Commands {
 
         /// The WHEP bearer token
         token: Option<String>,
+
+        /// STUN or TURN server to gather candidates from
+        #[arg(long = "ice-server")]
+        ice_servers: Vec<IceServer>,
     },
 }
 
@@ -113,93 +536,1028 @@ async fn main() -> Result<(), Error> {
     )?;
 
     match args.commands {
-        Commands::Stream { url, token } => stream(url, token).await?,
-        Commands::PlayWHIP {} => play_whip().await,
-        Commands::PlayWHEP { url, token } => play_whep(url, token).await?,
+        Commands::Stream {
+            url,
+            token,
+            encoder,
+            capture,
+            audio,
+            bitrate,
+            ice_servers,
+            simulcast,
+        } => {
+            stream(
+                url,
+                token,
+                encoder,
+                capture,
+                audio,
+                bitrate,
+                ice_servers,
+                simulcast,
+            )
+            .await?
+        }
+        Commands::Serve {
+            encoder,
+            capture,
+            audio,
+            listen,
+        } => serve(encoder, capture, audio, listen).await?,
+        Commands::Relay { listen } => relay(listen).await?,
+        Commands::PlayWHIP {
+            max_publishers,
+            auth,
+            listen,
+        } => play_whip(max_publishers, auth, listen).await?,
+        Commands::PlayWHEP {
+            url,
+            token,
+            ice_servers,
+        } => play_whep(url, token, ice_servers).await?,
     }
 
     Ok(())
 }
 
-async fn stream(url: String, token: Option<String>) -> Result<()> {
+#[allow(clippy::too_many_arguments)]
+async fn stream(
+    url: String,
+    token: Option<String>,
+    encoder_kind: EncoderKind,
+    capture: CaptureArgs,
+    audio: AudioArgs,
+    bitrate: BitrateArgs,
+    ice_servers: Vec<IceServer>,
+    simulcast: bool,
+) -> Result<()> {
+    if !(bitrate.min_bitrate..=bitrate.max_bitrate).contains(&bitrate.bitrate) {
+        bail!("--bitrate has to be between --min-bitrate and --max-bitrate");
+    }
     let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let (keyframe_tx, keyframe_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (bitrate_tx, bitrate_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (layers_tx, layers_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (join_handle, audio_handle, has_audio) = spawn_capture(
+        encoder_kind,
+        capture,
+        &audio,
+        tx,
+        keyframe_rx,
+        bitrate.bitrate * 1000,
+        bitrate_rx,
+        simulcast,
+        layers_rx,
+    )?;
+    let rids: Vec<&str> = match simulcast {
+        true => SIMULCAST_LAYERS.iter().map(|layer| layer.0).collect(),
+        false => Vec::new(),
+    };
+    let bitrate = whip::AdaptiveBitrate {
+        start: bitrate.bitrate * 1000,
+        min: bitrate.min_bitrate * 1000,
+        max: bitrate.max_bitrate * 1000,
+        tx: bitrate_tx,
+    };
+
+    tokio::select! {
+        _ = whip::publish(&url, token, has_audio, &rids, &ice_servers, rx, keyframe_tx, bitrate, layers_tx) => {},
+        res = join_handle => {
+            res??
+        }
+        res = audio_handle, if has_audio => {
+            res??
+        }
+        _ = tokio::signal::ctrl_c() => {
+            // Dropping the publisher DELETEs its session
+            info!("Interrupted, closing session");
+        }
+    }
+
+    Ok(())
+}
+
+// How far in percent the bandwidth estimate has to move before the video
+// bitrate follows, so the encoder isn't retargeted on every estimate
+const BITRATE_STEP_PERCENT: usize = 15;
+
+// Simulcast layers as RID, what the width and height are divided by and
+// the layer's share of the video bitrate, full size first
+const SIMULCAST_LAYERS: [(&str, u32, usize); 3] = [("h", 1, 8), ("m", 2, 3), ("l", 4, 1)];
+
+/// One encoding of the captured video, the only one or a simulcast layer
+struct VideoLayer {
+    rid: Option<&'static str>,
+    weight: usize,
+    scaler: Option<Scaler>,
+    encoder: Option<Encoder>,
+}
+
+type CaptureHandle = tokio::task::JoinHandle<Result<()>>;
+
+/// Capture and encode video, and audio if configured, into `tx` on blocking
+/// threads. Each message on `keyframe_rx` asks for a keyframe, video starts
+/// at `bit_rate` and follows what comes in on `bitrate_rx`. With `simulcast`
+/// the bitrate is split over the layers of SIMULCAST_LAYERS, until the RIDs
+/// on `layers_rx` narrow them down to what the server takes. Returns both
+/// threads and whether there is audio
+#[allow(clippy::too_many_arguments)]
+fn spawn_capture(
+    encoder_kind: EncoderKind,
+    capture: CaptureArgs,
+    audio: &AudioArgs,
+    tx: tokio::sync::mpsc::UnboundedSender<EncodedPacket>,
+    mut keyframe_rx: tokio::sync::mpsc::UnboundedReceiver<()>,
+    bit_rate: u64,
+    mut bitrate_rx: tokio::sync::mpsc::UnboundedReceiver<u64>,
+    simulcast: bool,
+    mut layers_rx: tokio::sync::mpsc::UnboundedReceiver<Vec<String>>,
+) -> Result<(CaptureHandle, CaptureHandle, bool)> {
+    if audio.opus_fec > 0 && matches!(audio.audio, AudioKind::None) {
+        bail!("--opus-fec requires --audio");
+    }
+
+    let start = Instant::now();
+
+    let audio_source = create_audio_source(audio, &capture)?;
+    let has_audio = audio_source.is_some();
+    let opus_fec = audio.opus_fec;
+    let audio_tx = tx.clone();
+    let audio_handle = tokio::task::spawn_blocking(move || -> Result<()> {
+        let Some(mut source) = audio_source else {
+            return Ok(());
+        };
+
+        let mut encoder = AudioEncoder::new(128 * 1000, opus_fec)?;
+        let mut anchor = None;
+        loop {
+            let frame = match source.get_frame() {
+                Ok(frame) => frame,
+                Err(err) if is_end_of_input(&err) => return Ok(()),
+                Err(err) => return Err(err),
+            };
+
+            for packet in encoder.encode(&frame)? {
+                // Audio is timed by its sample count, anchored to the shared
+                // start the first time a packet comes out
+                let pts = Duration::from_secs_f64(
+                    packet.pts().unwrap_or(0).max(0) as f64 / AudioEncoder::SAMPLE_RATE as f64,
+                );
+                let anchor = *anchor.get_or_insert_with(|| start.elapsed().saturating_sub(pts));
+                audio_tx
+                    .send(EncodedPacket {
+                        kind: MediaKind::Audio,
+                        packet,
+                        pts: anchor + pts,
+                        rid: None,
+                    })
+                    .unwrap();
+            }
+        }
+    });
 
     let join_handle = tokio::task::spawn_blocking(move || -> Result<()> {
-        let mut encoder: Option<Encoder> = None;
-        let mut source: Box<dyn Source + Send + Sync> =
-            Box::new(source::dxdup::DisplayDuplicator::new()?);
-
-        let ensure_encoder = |encoder: &mut Option<Encoder>,
-                              width: u32,
-                              height: u32,
-                              hw_frames: *mut AVBufferRef|
+        let mut layers: Vec<VideoLayer> = match simulcast {
+            true => SIMULCAST_LAYERS
+                .iter()
+                .map(|&(rid, divisor, weight)| VideoLayer {
+                    rid: Some(rid),
+                    weight,
+                    scaler: (divisor > 1).then(|| Scaler::new(divisor)),
+                    encoder: None,
+                })
+                .collect(),
+            false => vec![VideoLayer {
+                rid: None,
+                weight: 1,
+                scaler: None,
+                encoder: None,
+            }],
+        };
+        let mut total_weight: usize = layers.iter().map(|layer| layer.weight).sum();
+        let mut bit_rate = bit_rate as usize;
+        let mut framerate = capture.framerate;
+        let mut source: Box<dyn Source + Send> = match &capture.input {
+            Some(input) => {
+                let file = source::file::FileSource::new(input, capture.looping)?;
+                // Simulcast needs every layer encoded from decoded frames
+                if !capture.reencode && !simulcast && file.passthrough_compatible() {
+                    info!("Passing through H264 from {}", input);
+                    return stream_packets(file, tx, start);
+                }
+
+                framerate = file.framerate();
+                Box::new(file)
+            }
+            None => create_source(&capture)?,
+        };
+
+        let mut encoder_kind = encoder_kind;
+
+        let mut ensure_encoder = |encoder: &mut Option<Encoder>,
+                                  width: u32,
+                                  height: u32,
+                                  bit_rate: usize,
+                                  hw_frames: *mut AVBufferRef|
          -> Result<()> {
             if let Some(enc) = encoder {
-                if enc.dimensions() != (width, height) {
-                    encoder.replace(create_encoder(width, height, hw_frames)?);
+                if enc.dimensions() == (width, height) {
+                    return Ok(());
                 }
-            } else {
-                encoder.replace(create_encoder(width, height, hw_frames)?);
             }
 
+            // Once auto has settled on an encoder stick with it on resizes
+            let (kind, enc) =
+                create_encoder(encoder_kind, width, height, framerate, bit_rate, hw_frames)?;
+            encoder_kind = kind;
+            encoder.replace(enc);
+
             Ok(())
         };
-        let start = Instant::now();
+        let mut frame_index = 0;
         loop {
             // Pull frame from duplicator
-            let frame = source.get_frame()?;
+            let mut frame = match source.get_frame() {
+                Ok(frame) => frame,
+                Err(err) if is_end_of_input(&err) => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            // Sources stamp frames in their own time base, re-stamp them in
+            // the encoder's 1/framerate time base
+            frame.set_pts(Some(frame_index));
+            frame_index += 1;
             let hw_frames = unsafe { (*frame.as_ptr()).hw_frames_ctx };
-            // Fetch encoder or create it
-            ensure_encoder(&mut encoder, frame.width(), frame.height(), hw_frames)?;
-            if let Some(encoder) = &mut encoder {
-                // Encode frame
-                if let Some(packet) = encoder.encode(&frame)? {
-                    tx.send(EncodedPacket(packet, start)).unwrap();
+
+            // Follow the bandwidth estimate once it has moved far enough,
+            // encoders that can't retarget live are reopened at the new rate
+            while let Ok(target) = bitrate_rx.try_recv() {
+                bit_rate = target as usize;
+            }
+            while let Ok(rids) = layers_rx.try_recv() {
+                layers.retain(|layer| layer.rid.is_none_or(|rid| rids.iter().any(|r| r == rid)));
+                total_weight = layers.iter().map(|layer| layer.weight).sum();
+                info!("Encoding {} video layer(s)", layers.len());
+            }
+            let mut keyframe_requested = false;
+            while keyframe_rx.try_recv().is_ok() {
+                keyframe_requested = true;
+            }
+
+            for layer in &mut layers {
+                let layer_bit_rate = bit_rate * layer.weight / total_weight;
+                let reopen = match &mut layer.encoder {
+                    Some(encoder) => {
+                        let current = encoder.bit_rate();
+                        let moved =
+                            current.abs_diff(layer_bit_rate) * 100 > current * BITRATE_STEP_PERCENT;
+                        if moved {
+                            info!(
+                                "Changing video bitrate{} to {} kbps",
+                                layer
+                                    .rid
+                                    .map(|rid| format!(" of {rid}"))
+                                    .unwrap_or_default(),
+                                layer_bit_rate / 1000
+                            );
+                        }
+                        moved && !encoder.set_bit_rate(layer_bit_rate)
+                    }
+                    None => false,
+                };
+                if reopen {
+                    layer.encoder = None;
+                }
+
+                let mut scaled;
+                let frame = match &mut layer.scaler {
+                    Some(scaler) => {
+                        scaled = scaler.scale(&frame)?;
+                        &mut scaled
+                    }
+                    None => &mut frame,
+                };
+                let hw_frames = unsafe { (*frame.as_ptr()).hw_frames_ctx };
+
+                // Fetch encoder or create it
+                ensure_encoder(
+                    &mut layer.encoder,
+                    frame.width(),
+                    frame.height(),
+                    layer_bit_rate,
+                    hw_frames,
+                )?;
+                if let Some(encoder) = &mut layer.encoder {
+                    // Keyframe requests don't say which layer, every layer sends one
+                    if keyframe_requested {
+                        encoder.request_keyframe();
+                    }
+
+                    // Encode frame
+                    if let Some(packet) = encoder.encode(frame)? {
+                        tx.send(EncodedPacket {
+                            kind: MediaKind::Video,
+                            packet,
+                            pts: start.elapsed(),
+                            rid: layer.rid,
+                        })
+                        .unwrap();
+                    }
                 }
             }
         }
     });
 
+    Ok((join_handle, audio_handle, has_audio))
+}
+
+/// A WHEP viewer of `serve`, fed from the next keyframe on
+struct Viewer {
+    tx: tokio::sync::mpsc::UnboundedSender<EncodedPacket>,
+    started: bool,
+}
+
+/// Send a copy of the packet to every viewer, dropping those that have gone away
+fn fan_out(viewers: &mut Vec<Viewer>, packet: &EncodedPacket) {
+    viewers.retain_mut(|viewer| {
+        if packet.kind == MediaKind::Video && !viewer.started {
+            if !packet.packet.is_key() {
+                return true;
+            }
+            viewer.started = true;
+        }
+
+        viewer.tx.send(packet.clone()).is_ok()
+    });
+}
+
+async fn serve(
+    encoder_kind: EncoderKind,
+    capture: CaptureArgs,
+    audio: AudioArgs,
+    listen: ListenArgs,
+) -> Result<()> {
+    let listener = listener(&listen)?;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let (keyframe_tx, keyframe_rx) = tokio::sync::mpsc::unbounded_channel();
+    // Viewers share one encoding, so it can't follow any one viewer's bandwidth
+    let (_, bitrate_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (_, layers_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (join_handle, audio_handle, has_audio) = spawn_capture(
+        encoder_kind,
+        capture,
+        &audio,
+        tx,
+        keyframe_rx,
+        5000 * 1000,
+        bitrate_rx,
+        false,
+        layers_rx,
+    )?;
+
+    let viewers: Arc<Mutex<Vec<Viewer>>> = Arc::default();
+    let sessions = Sessions::default();
+
+    // Encode once, every viewer gets a copy of each packet
+    let fan_out_viewers = viewers.clone();
+    tokio::task::spawn(async move {
+        while let Some(packet) = rx.recv().await {
+            fan_out(&mut fan_out_viewers.lock().unwrap(), &packet);
+        }
+    });
+
+    println!("Serving WHEP on {}/", listener.url());
+    let app = Router::new()
+        .route(
+            "/",
+            post({
+                let sessions = sessions.clone();
+                move |headers: HeaderMap, offer: String| {
+                    whep_handler(viewers, keyframe_tx, sessions, headers, offer)
+                }
+            })
+            .options(listen::options),
+        )
+        .merge(session_routes(sessions));
+
     tokio::select! {
-        _ = whip::publish(&url, token, rx) => {},
+        res = listener.serve(app) => res?,
         res = join_handle => {
             res??
         }
+        res = audio_handle, if has_audio => {
+            res??
+        }
+        _ = tokio::signal::ctrl_c() => {
+            info!("Interrupted, stopping");
+        }
+    }
+
+    Ok(())
+}
+
+async fn whep_handler(
+    viewers: Arc<Mutex<Vec<Viewer>>>,
+    keyframe_tx: tokio::sync::mpsc::UnboundedSender<()>,
+    sessions: Sessions,
+    headers: HeaderMap,
+    offer: String,
+) -> Response<String> {
+    if let Err(status) = check_offer_headers(&headers) {
+        return empty_response(status);
+    }
+
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let (answer, session) = match whip::serve_as_server(rx, keyframe_tx.clone(), offer) {
+        Ok(served) => served,
+        Err(err) => {
+            info!("Rejected WHEP offer: {:?}", err);
+            return empty_response(offer_error_status(&err));
+        }
+    };
+
+    viewers.lock().unwrap().push(Viewer { tx, started: false });
+    // Get the new viewer going without waiting out the GOP
+    let _ = keyframe_tx.send(());
+    session_created(&sessions, new_session_id(), answer, session)
+}
+
+// Relayed streams, keyed by stream key
+type RelayStreams = Arc<Mutex<HashMap<String, RelayStream>>>;
+
+struct RelayStream {
+    viewers: Vec<Viewer>,
+    // Viewers ask for keyframes here, they are passed on to whoever publishes
+    keyframe_tx: tokio::sync::mpsc::UnboundedSender<()>,
+    publisher: Arc<Mutex<Option<tokio::sync::mpsc::UnboundedSender<()>>>>,
+}
+
+impl RelayStream {
+    fn new() -> Self {
+        let (keyframe_tx, mut keyframe_rx) = tokio::sync::mpsc::unbounded_channel();
+        let publisher: Arc<Mutex<Option<tokio::sync::mpsc::UnboundedSender<()>>>> = Arc::default();
+
+        let forward_to = publisher.clone();
+        tokio::task::spawn(async move {
+            while keyframe_rx.recv().await.is_some() {
+                if let Some(publisher) = &*forward_to.lock().unwrap() {
+                    let _ = publisher.send(());
+                }
+            }
+        });
+
+        Self {
+            viewers: Vec::new(),
+            keyframe_tx,
+            publisher,
+        }
+    }
+
+    /// Whether a publisher is sending to the stream
+    fn is_live(&self) -> bool {
+        self.publisher
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|tx| !tx.is_closed())
+    }
+
+    /// Forget viewers and a publisher whose sessions have ended, false once
+    /// nothing is left of the stream
+    fn prune(&mut self) -> bool {
+        self.viewers.retain(|viewer| !viewer.tx.is_closed());
+        let mut publisher = self.publisher.lock().unwrap();
+        if publisher.as_ref().is_some_and(|tx| tx.is_closed()) {
+            *publisher = None;
+        }
+
+        publisher.is_some() || !self.viewers.is_empty()
+    }
+}
+
+fn prune_relay_streams(streams: &mut HashMap<String, RelayStream>) {
+    streams.retain(|_, stream| stream.prune());
+}
+
+async fn relay(listen: ListenArgs) -> Result<()> {
+    let listener = listener(&listen)?;
+    let streams = RelayStreams::default();
+    let sessions = Sessions::default();
+
+    let url = listener.url();
+    println!(
+        "Relaying WHIP on {}/whip/<key> to WHEP on {}/whep/<key>",
+        url, url
+    );
+    let app = Router::new()
+        .route(
+            "/whip/:key",
+            post({
+                let streams = streams.clone();
+                let sessions = sessions.clone();
+                move |Path(key): Path<String>, headers: HeaderMap, offer: String| {
+                    relay_whip_handler(streams, sessions, key, headers, offer)
+                }
+            })
+            .options(listen::options),
+        )
+        .route(
+            "/whep/:key",
+            post({
+                let sessions = sessions.clone();
+                move |Path(key): Path<String>, headers: HeaderMap, offer: String| {
+                    relay_whep_handler(streams, sessions, key, headers, offer)
+                }
+            })
+            .options(listen::options),
+        )
+        .merge(session_routes(sessions));
+
+    tokio::select! {
+        res = listener.serve(app) => res?,
+        _ = tokio::signal::ctrl_c() => {
+            info!("Interrupted, stopping");
+        }
+    }
+
+    Ok(())
+}
+
+async fn relay_whip_handler(
+    streams: RelayStreams,
+    sessions: Sessions,
+    key: String,
+    headers: HeaderMap,
+    offer: String,
+) -> Response<String> {
+    if let Err(status) = check_offer_headers(&headers) {
+        return empty_response(status);
+    }
+
+    // Held until the publisher is in, so two offers for one key can't both
+    // get past the check
+    let mut relay_streams = streams.lock().unwrap();
+    prune_relay_streams(&mut relay_streams);
+    if relay_streams.get(&key).is_some_and(RelayStream::is_live) {
+        info!("Rejected publisher, {:?} is already being published", key);
+        return empty_response(409);
+    }
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let (keyframe_tx, keyframe_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (answer, session) = match whip::relay_as_server(tx, keyframe_rx, offer) {
+        Ok(relayed) => relayed,
+        Err(err) => {
+            info!("Rejected WHIP offer for {}: {:?}", key, err);
+            return empty_response(offer_error_status(&err));
+        }
+    };
+
+    info!("Publisher connected to {}", key);
+    let stream = relay_streams
+        .entry(key.clone())
+        .or_insert_with(RelayStream::new);
+    // Viewers already waiting pick the new publisher up at its first keyframe
+    for viewer in &mut stream.viewers {
+        viewer.started = false;
+    }
+    *stream.publisher.lock().unwrap() = Some(keyframe_tx);
+    drop(relay_streams);
+
+    tokio::task::spawn(async move {
+        while let Some(packet) = rx.recv().await {
+            if let Some(stream) = streams.lock().unwrap().get_mut(&key) {
+                fan_out(&mut stream.viewers, &packet);
+            }
+        }
+        info!("Publisher left {}", key);
+        prune_relay_streams(&mut streams.lock().unwrap());
+    });
+
+    session_created(&sessions, new_session_id(), answer, session)
+}
+
+async fn relay_whep_handler(
+    streams: RelayStreams,
+    sessions: Sessions,
+    key: String,
+    headers: HeaderMap,
+    offer: String,
+) -> Response<String> {
+    if let Err(status) = check_offer_headers(&headers) {
+        return empty_response(status);
+    }
+
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    // Added right away so pruning can't drop the stream in the meantime. If
+    // the offer is rejected rx is gone and the viewer is pruned later
+    let keyframe_tx = {
+        let mut streams = streams.lock().unwrap();
+        prune_relay_streams(&mut streams);
+        let stream = streams.entry(key.clone()).or_insert_with(RelayStream::new);
+        stream.viewers.push(Viewer { tx, started: false });
+        stream.keyframe_tx.clone()
+    };
+    let (answer, session) = match whip::serve_as_server(rx, keyframe_tx.clone(), offer) {
+        Ok(served) => served,
+        Err(err) => {
+            info!("Rejected WHEP offer for {}: {:?}", key, err);
+            return empty_response(offer_error_status(&err));
+        }
+    };
+
+    // Get the new viewer going without waiting out the publisher's GOP
+    let _ = keyframe_tx.send(());
+    session_created(&sessions, new_session_id(), answer, session)
+}
+
+fn stream_packets(
+    mut file: source::file::FileSource,
+    tx: tokio::sync::mpsc::UnboundedSender<EncodedPacket>,
+    start: Instant,
+) -> Result<()> {
+    loop {
+        match file.get_packet() {
+            Ok(packet) => tx
+                .send(EncodedPacket {
+                    kind: MediaKind::Video,
+                    packet,
+                    pts: start.elapsed(),
+                    rid: None,
+                })
+                .unwrap(),
+            Err(err) if is_end_of_input(&err) => return Ok(()),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn is_end_of_input(err: &Error) -> bool {
+    matches!(
+        err.downcast_ref::<ffmpeg_next::Error>(),
+        Some(ffmpeg_next::Error::Eof)
+    )
+}
+
+// Sessions started by play-whip, keyed by the id in their resource URL
+type Sessions = Arc<Mutex<HashMap<String, whip::Session>>>;
+
+// Session id of the publisher on each stream key
+type Publishers = Arc<Mutex<HashMap<String, String>>>;
+
+/// What every POST to the play-whip server shares
+#[derive(Clone)]
+struct WhipServer {
+    streams_tx: mpsc::Sender<(String, mpsc::Receiver<PlayerFrame>)>,
+    sessions: Sessions,
+    publishers: Publishers,
+    max_publishers: usize,
+    auth: Arc<Auth>,
+}
+
+async fn whip_handler(
+    server: WhipServer,
+    uri: Uri,
+    headers: HeaderMap,
+    offer: String,
+) -> Response<String> {
+    let grant = match server.auth.check(&headers, uri.path()) {
+        Ok(grant) => grant,
+        Err(err) => {
+            info!("Rejected publisher on {}: {:?}", uri.path(), err);
+            return auth_error_response(&err);
+        }
+    };
+    if let Err(status) = check_offer_headers(&headers) {
+        return empty_response(status);
+    }
+
+    // The path is the stream key, publishers to the root share the empty key
+    let key = uri.path().trim_start_matches('/').to_string();
+
+    let mut publishers = server.publishers.lock().unwrap();
+    {
+        let sessions = server.sessions.lock().unwrap();
+        publishers.retain(|_, id| {
+            sessions
+                .get(id)
+                .is_some_and(|session| !session.is_finished())
+        });
+    }
+
+    if publishers.contains_key(&key) {
+        info!("Rejected publisher, {:?} is already being published", key);
+        return empty_response(409);
+    }
+    if publishers.len() >= server.max_publishers {
+        info!("Rejected publisher, already playing {}", publishers.len());
+        return empty_response(503);
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let (answer, mut session) = match whip::subscribe_as_server(tx, offer) {
+        Ok(subscribed) => subscribed,
+        Err(err) => {
+            info!("Rejected WHIP offer for {:?}: {:?}", key, err);
+            return empty_response(offer_error_status(&err));
+        }
+    };
+    // Only a request that made it this far uses up a single use token
+    if let Err(err) = server.auth.redeem(grant) {
+        info!("Rejected publisher on {}: {:?}", uri.path(), err);
+        session.close();
+        return auth_error_response(&err);
+    }
+    session.token = auth::bearer_token(&headers).map(str::to_string);
+    let _ = server.streams_tx.send((key.clone(), rx));
+
+    info!("Publisher connected to {:?}", key);
+    let id = new_session_id();
+    publishers.insert(key, id.clone());
+    session_created(&server.sessions, id, answer, session)
+}
+
+fn auth_error_response(err: &auth::AuthError) -> Response<String> {
+    let mut response = Response::builder().status(err.status());
+    if err.status() == 401 {
+        response = response.header(WWW_AUTHENTICATE, "Bearer");
+    }
+    response.body(String::new()).unwrap()
+}
+
+/// Check the headers of a POSTed offer, returning the status to reject it with
+fn check_offer_headers(headers: &HeaderMap) -> Result<(), u16> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next());
+    if !content_type.is_some_and(|value| value.trim().eq_ignore_ascii_case("application/sdp")) {
+        return Err(415);
+    }
+
+    // The answer is SDP, a client that won't take it can't be answered
+    if let Some(accept) = headers.get(ACCEPT).and_then(|value| value.to_str().ok()) {
+        let accepted = accept
+            .split(',')
+            .filter_map(|range| range.split(';').next())
+            .any(|range| {
+                ["application/sdp", "application/*", "*/*"]
+                    .iter()
+                    .any(|accepted| range.trim().eq_ignore_ascii_case(accepted))
+            });
+        if !accepted {
+            return Err(406);
+        }
     }
 
     Ok(())
 }
 
-async fn whip_handler(tx: mpsc::Sender<ffmpeg_next::frame::Video>, offer: String) -> Response<String> {
-    let answer = whip::subscribe_as_server(tx, offer);
+/// Offers that don't parse are bad requests, ones that parse but can't be
+/// answered are unprocessable
+fn offer_error_status(err: &WebrtcError) -> u16 {
+    match err {
+        WebrtcError::SdpError => 400,
+        WebrtcError::WebrtcError(_) => 422,
+        _ => 500,
+    }
+}
+
+fn empty_response(status: u16) -> Response<String> {
+    Response::builder()
+        .status(status)
+        .body(String::new())
+        .unwrap()
+}
+
+fn new_session_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// Bind the listener, with TLS if a certificate was given or asked for
+fn listener(listen: &ListenArgs) -> Result<listen::Listener> {
+    let tls = match (&listen.tls_cert, &listen.tls_key) {
+        (Some(cert), Some(key)) => Some(listen::load_tls(cert, key)?),
+        _ if listen.self_signed => Some(listen::self_signed_tls()?),
+        _ => None,
+    };
+
+    listen::Listener::bind(listen.bind, tls, listen::cors(&listen.allow_origins)?)
+}
+
+/// Track a new session and answer with its resource URL
+fn session_created(
+    sessions: &Sessions,
+    id: String,
+    answer: String,
+    session: whip::Session,
+) -> Response<String> {
+    let etag = session.etag.clone();
+
+    let mut sessions = sessions.lock().unwrap();
+    sessions.retain(|_, session| !session.is_finished());
+    sessions.insert(id.clone(), session);
+
     Response::builder()
         .status(201)
-        .header("Location", "/")
+        .header(CONTENT_TYPE, "application/sdp")
+        .header("Location", format!("/session/{}", id))
+        .header("ETag", etag)
         .body(answer)
         .unwrap()
 }
 
-async fn play_whip() {
-    println!("Listening for WHIP Requests on 0.0.0.0:1337");
-    let (tx, rx): (mpsc::Sender<ffmpeg_next::frame::Video>, mpsc::Receiver<ffmpeg_next::frame::Video>) = mpsc::channel();
+/// DELETE and PATCH on the resource URLs handed out by session_created
+fn session_routes(sessions: Sessions) -> Router {
+    Router::new().route(
+        "/session/:id",
+        delete({
+            let sessions = sessions.clone();
+            move |Path(id): Path<String>, headers: HeaderMap| {
+                whip_delete_handler(sessions, id, headers)
+            }
+        })
+        .patch(
+            move |Path(id): Path<String>, headers: HeaderMap, fragment: String| {
+                whip_patch_handler(sessions, id, headers, fragment)
+            },
+        ),
+    )
+}
+
+/// Sessions created with a bearer token only take requests carrying the same one
+fn check_session_token(
+    session: &whip::Session,
+    headers: &HeaderMap,
+) -> Result<(), auth::AuthError> {
+    let Some(token) = &session.token else {
+        return Ok(());
+    };
+
+    match auth::bearer_token(headers) {
+        Some(sent) if auth::tokens_match(token, sent) => Ok(()),
+        Some(_) => Err(auth::AuthError::Forbidden(
+            "token does not match the session",
+        )),
+        None => Err(auth::AuthError::Missing),
+    }
+}
+
+async fn whip_delete_handler(
+    sessions: Sessions,
+    id: String,
+    headers: HeaderMap,
+) -> Response<String> {
+    let mut sessions = sessions.lock().unwrap();
+    let Some(session) = sessions.get(&id) else {
+        return empty_response(404);
+    };
+    if let Err(err) = check_session_token(session, &headers) {
+        info!("Rejected DELETE of session {}: {:?}", id, err);
+        return auth_error_response(&err);
+    }
+
+    if let Some(session) = sessions.remove(&id) {
+        info!("Session {} deleted", id);
+        session.close();
+    }
+    empty_response(200)
+}
 
+async fn whip_patch_handler(
+    sessions: Sessions,
+    id: String,
+    headers: HeaderMap,
+    fragment: String,
+) -> Response<String> {
+    {
+        let sessions = sessions.lock().unwrap();
+        let Some(session) = sessions.get(&id) else {
+            return empty_response(404);
+        };
+        if let Err(err) = check_session_token(session, &headers) {
+            info!("Rejected PATCH of session {}: {:?}", id, err);
+            return auth_error_response(&err);
+        }
+    }
+
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    if content_type != Some("application/trickle-ice-sdpfrag") {
+        return empty_response(415);
+    }
+
+    // Trickle must match the current ETag, an ICE restart is made against "*"
+    let Some(if_match) = headers.get(IF_MATCH).and_then(|value| value.to_str().ok()) else {
+        return empty_response(428);
+    };
+    let patched = {
+        let sessions = sessions.lock().unwrap();
+        let Some(session) = sessions.get(&id) else {
+            return empty_response(404);
+        };
+        if if_match != "*" && if_match != session.etag {
+            return empty_response(412);
+        }
+        session.patch(fragment)
+    };
+
+    match patched.await {
+        Ok(None) => empty_response(204),
+        Ok(Some(answer)) => {
+            let etag = whip::new_etag();
+            if let Some(session) = sessions.lock().unwrap().get_mut(&id) {
+                session.etag = etag.clone();
+            }
+
+            Response::builder()
+                .status(200)
+                .header(CONTENT_TYPE, "application/trickle-ice-sdpfrag")
+                .header(ETAG, etag)
+                .body(answer)
+                .unwrap()
+        }
+        Err(err) => {
+            info!("Session {} rejected PATCH: {:?}", id, err);
+            empty_response(400)
+        }
+    }
+}
+
+async fn play_whip(max_publishers: usize, auth: AuthArgs, listen: ListenArgs) -> Result<()> {
+    let auth = Auth::new(
+        auth.tokens,
+        auth.jwt_secret.as_deref(),
+        auth.jwt_public_key.as_deref(),
+    )?;
+    let listener = listener(&listen)?;
+
+    println!("Listening for WHIP Requests on {}/", listener.url());
+    let (streams_tx, streams) = mpsc::channel();
+    let sessions = Sessions::default();
+    let server = WhipServer {
+        streams_tx,
+        sessions: sessions.clone(),
+        publishers: Publishers::default(),
+        max_publishers,
+        auth: Arc::new(auth),
+    };
+    let handler = move |uri: Uri, headers: HeaderMap, offer: String| {
+        whip_handler(server, uri, headers, offer)
+    };
+
+    let app = Router::new()
+        .route("/", post(handler.clone()).options(listen::options))
+        .route("/:key", post(handler).options(listen::options))
+        .merge(session_routes(sessions));
     tokio::task::spawn(async move {
-        axum::serve(
-            tokio::net::TcpListener::bind("0.0.0.0:1337").await.unwrap(),
-            Router::new().route("/", post(move |offer: String| whip_handler(tx, offer))),
-        )
-        .await
-        .unwrap();
+        listener.serve(app).await.unwrap();
     });
 
-    render_video(rx);
+    render_streams(streams);
+
+    Ok(())
 }
 
-async fn play_whep(url: String, token: Option<String>) -> Result<()> {
-    let (tx, rx): (mpsc::Sender<ffmpeg_next::frame::Video>, mpsc::Receiver<ffmpeg_next::frame::Video>) = mpsc::channel();
+async fn play_whep(url: String, token: Option<String>, ice_servers: Vec<IceServer>) -> Result<()> {
+    let (tx, rx): (mpsc::Sender<PlayerFrame>, mpsc::Receiver<PlayerFrame>) = mpsc::channel();
 
-    whip::subscribe_as_client(tx, &url, token).await;
+    whip::subscribe_as_client(tx, &url, token, &ice_servers).await;
     render_video(rx);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use str0m::{media::Direction, Rtc};
+
+    fn publisher_offer() -> String {
+        let mut rtc = Rtc::new();
+        let mut change = rtc.sdp_api();
+        change.add_media(MediaKind::Video, Direction::SendOnly, None, None);
+        let (offer, _) = change.apply().unwrap();
+        offer.to_sdp_string()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn relay_rejects_second_publisher() {
+        let streams = RelayStreams::default();
+        let sessions = Sessions::default();
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, "application/sdp".parse().unwrap());
+
+        let publish = || {
+            relay_whip_handler(
+                streams.clone(),
+                sessions.clone(),
+                "key".to_string(),
+                headers.clone(),
+                publisher_offer(),
+            )
+        };
+        assert_eq!(publish().await.status(), 201);
+        assert_eq!(publish().await.status(), 409);
+    }
+}
@@ -1,78 +1,358 @@
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
+use sdl2::render::Texture;
+use sdl2::AudioSubsystem;
+use std::collections::VecDeque;
 use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
-pub fn render_video(rx: mpsc::Receiver<ffmpeg_next::frame::Video>) {
-    match rx.recv() {
-        Ok(first_frame) => {
-            let sdl_context = sdl2::init().unwrap();
-            let video_subsystem = sdl_context.video().unwrap();
-            let window = video_subsystem
-                .window("bitwhip", first_frame.width(), first_frame.height())
-                .position_centered()
-                .build()
-                .unwrap();
-
-            let mut canvas = window.into_canvas().build().unwrap();
-            let mut event_pump = sdl_context.event_pump().unwrap();
-            let texture_creator = canvas.texture_creator();
-            let mut texture = texture_creator
-                .create_texture_streaming(PixelFormatEnum::IYUV, first_frame.width(), first_frame.height())
-                .map_err(|e| e.to_string())
-                .expect("No error");
-
-            let buffer_size: i32;
-            unsafe {
-                buffer_size = ffmpeg_sys_next::av_image_get_buffer_size(
-                    first_frame.format().into(),
-                    first_frame.width() as i32,
-                    first_frame.height() as i32,
-                    32,
-                );
+pub const AUDIO_SAMPLE_RATE: u32 = 48000;
+pub const AUDIO_CHANNELS: u16 = 2;
+
+// Past this much queued audio the queue is dropped to get back to low latency
+const MAX_AUDIO_LATENCY: Duration = Duration::from_millis(250);
+
+// Audio and video this close together count as in sync
+const SYNC_TOLERANCE: Duration = Duration::from_millis(20);
+
+// Pictures waiting on the audio longer than this are shown anyway, e.g. when
+// the audio stalls or the two clocks jump apart
+const MAX_VIDEO_DELAY: Duration = Duration::from_secs(1);
+
+// Audio that stopped coming in this long ago no longer paces the video
+const AUDIO_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Frames carry when they were captured, from their RTP timestamps, on a
+/// clock that is shared by the audio and video of a stream
+pub enum PlayerFrame {
+    /// A decoded picture and when it was captured
+    Video(ffmpeg_next::frame::Video, Instant),
+    /// Packed f32 stereo samples at AUDIO_SAMPLE_RATE and when the first was captured
+    Audio(ffmpeg_next::frame::Audio, Instant),
+}
+
+fn samples_duration(samples: usize) -> Duration {
+    Duration::from_secs_f64(samples as f64 / AUDIO_SAMPLE_RATE as f64)
+}
+
+fn audio_latency(queue: &AudioQueue<f32>) -> Duration {
+    let bytes_per_second =
+        AUDIO_SAMPLE_RATE as usize * AUDIO_CHANNELS as usize * std::mem::size_of::<f32>();
+    Duration::from_secs_f64(queue.size() as f64 / bytes_per_second as f64)
+}
+
+/// A stream being played, shown in its own tile of the window
+struct Tile<'a> {
+    name: String,
+    rx: mpsc::Receiver<PlayerFrame>,
+    audio_queue: AudioQueue<f32>,
+    // Capture time just past the last queued sample and when it was queued
+    audio_end: Option<(Instant, Instant)>,
+    // Pictures with their capture and arrival times
+    pending: VecDeque<(ffmpeg_next::frame::Video, Instant, Instant)>,
+    texture: Option<Texture<'a>>,
+}
+
+impl Tile<'_> {
+    fn new(name: String, rx: mpsc::Receiver<PlayerFrame>, audio_queue: AudioQueue<f32>) -> Self {
+        Self {
+            name,
+            rx,
+            audio_queue,
+            audio_end: None,
+            pending: VecDeque::new(),
+            texture: None,
+        }
+    }
+
+    /// Take in newly decoded frames, false once the stream has ended
+    fn receive(&mut self) -> bool {
+        loop {
+            match self.rx.try_recv() {
+                Ok(PlayerFrame::Video(frame, captured)) => self.push_video(frame, captured),
+                Ok(PlayerFrame::Audio(frame, captured)) => self.queue_audio(&frame, captured),
+                Err(mpsc::TryRecvError::Empty) => return true,
+                Err(mpsc::TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
+
+    fn push_video(&mut self, frame: ffmpeg_next::frame::Video, captured: Instant) {
+        // A picture behind the audio that is playing arrived too late to be
+        // in sync, hold the audio back by the difference
+        if let Some(behind) = self
+            .audio_clock()
+            .and_then(|clock| clock.checked_duration_since(captured))
+            .filter(|behind| *behind > SYNC_TOLERANCE)
+        {
+            let room = MAX_AUDIO_LATENCY.saturating_sub(audio_latency(&self.audio_queue));
+            self.queue_silence(behind.min(room));
+        }
+
+        self.pending.push_back((frame, captured, Instant::now()));
+    }
+
+    fn queue_audio(&mut self, frame: &ffmpeg_next::frame::Audio, captured: Instant) {
+        if audio_latency(&self.audio_queue) > MAX_AUDIO_LATENCY {
+            self.audio_queue.clear();
+        }
+
+        // Keep later samples at their time when packets were lost or skipped
+        if let Some(gap) = self
+            .audio_end
+            .and_then(|(end, _)| captured.checked_duration_since(end))
+            .filter(|gap| *gap > SYNC_TOLERANCE && *gap < MAX_AUDIO_LATENCY)
+        {
+            self.queue_silence(gap);
+        }
+
+        let len = frame.samples() * frame.channels() as usize * std::mem::size_of::<f32>();
+        let samples: Vec<f32> = frame.data(0)[..len]
+            .chunks_exact(std::mem::size_of::<f32>())
+            .map(|sample| f32::from_ne_bytes(sample.try_into().unwrap()))
+            .collect();
+        self.audio_queue.queue_audio(&samples).expect("queue audio");
+        self.audio_end = Some((captured + samples_duration(frame.samples()), Instant::now()));
+    }
+
+    fn queue_silence(&mut self, duration: Duration) {
+        let samples = (duration.as_secs_f64() * AUDIO_SAMPLE_RATE as f64) as usize;
+        let silence = vec![0f32; samples * AUDIO_CHANNELS as usize];
+        self.audio_queue.queue_audio(&silence).expect("queue audio");
+    }
+
+    /// Capture time of the audio that is playing right now, the samples still
+    /// queued are what separates it from the last one queued
+    fn audio_clock(&self) -> Option<Instant> {
+        let (end, queued) = self.audio_end?;
+        if queued.elapsed() > AUDIO_TIMEOUT {
+            return None;
+        }
+
+        end.checked_sub(audio_latency(&self.audio_queue))
+    }
+
+    /// The latest picture that is due. With audio playing that is the one
+    /// captured at the same time as what is heard, otherwise the newest
+    fn due(&mut self) -> Option<ffmpeg_next::frame::Video> {
+        let clock = self.audio_clock();
+        let mut due = None;
+        while let Some((_, captured, arrived)) = self.pending.front() {
+            let ready = match clock {
+                Some(clock) => *captured <= clock || arrived.elapsed() > MAX_VIDEO_DELAY,
+                None => true,
             };
+            if !ready {
+                break;
+            }
+            due = self.pending.pop_front().map(|(frame, ..)| frame);
+        }
+
+        due
+    }
+}
+
+fn open_audio_queue(audio_subsystem: &AudioSubsystem) -> AudioQueue<f32> {
+    let audio_queue: AudioQueue<f32> = audio_subsystem
+        .open_queue(
+            None,
+            &AudioSpecDesired {
+                freq: Some(AUDIO_SAMPLE_RATE as i32),
+                channels: Some(AUDIO_CHANNELS as u8),
+                samples: Some(480),
+            },
+        )
+        .unwrap();
+    audio_queue.resume();
+
+    audio_queue
+}
+
+fn upload_frame(texture: &mut Texture, frame: &ffmpeg_next::frame::Video) {
+    let buffer_size: i32;
+    unsafe {
+        buffer_size = ffmpeg_sys_next::av_image_get_buffer_size(
+            frame.format().into(),
+            frame.width() as i32,
+            frame.height() as i32,
+            32,
+        );
+    };
+
+    texture
+        .with_lock(None, |buffer: &mut [u8], _pitch: usize| unsafe {
+            let frame_ptr = *frame.as_ptr();
+            ffmpeg_sys_next::av_image_copy_to_buffer(
+                buffer.as_mut_ptr(),
+                buffer_size,
+                frame_ptr.data.as_ptr() as *mut _,
+                frame_ptr.linesize.as_ptr() as *mut _,
+                frame.format().into(),
+                frame_ptr.width,
+                frame_ptr.height,
+                32,
+            );
+        })
+        .expect("texture copy");
+}
+
+/// Split the window into a grid with a cell for each of `count` tiles
+fn tile_rects(width: u32, height: u32, count: usize) -> Vec<Rect> {
+    let columns = (count as f64).sqrt().ceil().max(1.0) as u32;
+    let rows = (count as u32).div_ceil(columns).max(1);
+    let (tile_width, tile_height) = (width / columns, height / rows);
+
+    (0..count as u32)
+        .map(|i| {
+            Rect::new(
+                ((i % columns) * tile_width) as i32,
+                ((i / columns) * tile_height) as i32,
+                tile_width,
+                tile_height,
+            )
+        })
+        .collect()
+}
+
+fn window_title(tiles: &[Tile]) -> String {
+    let names: Vec<&str> = tiles
+        .iter()
+        .map(|tile| tile.name.as_str())
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    if names.is_empty() {
+        "bitwhip".to_string()
+    } else {
+        format!("bitwhip - {}", names.join(", "))
+    }
+}
 
+/// Play a single stream
+pub fn render_video(rx: mpsc::Receiver<PlayerFrame>) {
+    let (streams_tx, streams) = mpsc::channel();
+    streams_tx.send((String::new(), rx)).unwrap();
+    drop(streams_tx);
 
-            'running: loop {
-                for event in event_pump.poll_iter() {
-                    match event {
-                        Event::Quit { .. }
-                        | Event::KeyDown {
-                            keycode: Some(Keycode::Escape),
-                            ..
-                        } => break 'running,
-                        _ => {}
-                    }
+    render_streams(streams);
+}
+
+/// Play every stream handed over on `streams` side by side, each with a name
+/// and its frames. A stream's tile goes away when its sender is dropped
+pub fn render_streams(streams: mpsc::Receiver<(String, mpsc::Receiver<PlayerFrame>)>) {
+    // The window is sized by the first picture of any stream, audio that
+    // shows up before it is dropped
+    let mut waiting = Vec::new();
+    let first_frame = 'waiting: loop {
+        let mut streams_open = true;
+        loop {
+            match streams.try_recv() {
+                Ok(stream) => waiting.push(stream),
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    streams_open = false;
+                    break;
                 }
+            }
+        }
+
+        let mut i = 0;
+        while i < waiting.len() {
+            match waiting[i].1.try_recv() {
+                Ok(PlayerFrame::Video(frame, captured)) => break 'waiting (i, frame, captured),
+                Ok(PlayerFrame::Audio(..)) => {}
+                Err(mpsc::TryRecvError::Empty) => i += 1,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    waiting.remove(i);
+                }
+            }
+        }
+
+        if !streams_open && waiting.is_empty() {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    };
+    let (first_index, first_frame, captured) = first_frame;
+
+    let sdl_context = sdl2::init().unwrap();
+    let video_subsystem = sdl_context.video().unwrap();
+    let window = video_subsystem
+        .window("bitwhip", first_frame.width(), first_frame.height())
+        .position_centered()
+        .resizable()
+        .build()
+        .unwrap();
+    let audio_subsystem = sdl_context.audio().unwrap();
+
+    let mut canvas = window.into_canvas().build().unwrap();
+    let mut event_pump = sdl_context.event_pump().unwrap();
+    let texture_creator = canvas.texture_creator();
+
+    let mut tiles: Vec<Tile> = waiting
+        .into_iter()
+        .map(|(name, rx)| Tile::new(name, rx, open_audio_queue(&audio_subsystem)))
+        .collect();
+    tiles[first_index].push_video(first_frame, captured);
+    canvas.window_mut().set_title(&window_title(&tiles)).ok();
+
+    'running: loop {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => break 'running,
+                _ => {}
+            }
+        }
+
+        let mut changed = false;
+        while let Ok((name, rx)) = streams.try_recv() {
+            changed = true;
+            tiles.push(Tile::new(name, rx, open_audio_queue(&audio_subsystem)));
+        }
+        let count = tiles.len();
+        tiles.retain_mut(|tile| tile.receive());
+        if changed || tiles.len() != count {
+            canvas.window_mut().set_title(&window_title(&tiles)).ok();
+        }
+
+        let (width, height) = canvas.output_size().expect("output size");
+        let rects = tile_rects(width, height, tiles.len());
+
+        canvas.clear();
+        for (tile, rect) in tiles.iter_mut().zip(rects) {
+            if let Some(frame) = tile.due() {
+                // Streams can change resolution midway, e.g. after a new keyframe
+                let resized = tile.texture.as_ref().is_none_or(|texture| {
+                    let query = texture.query();
+                    (query.width, query.height) != (frame.width(), frame.height())
+                });
+                if resized {
+                    tile.texture = Some(
+                        texture_creator
+                            .create_texture_streaming(
+                                PixelFormatEnum::IYUV,
+                                frame.width(),
+                                frame.height(),
+                            )
+                            .map_err(|e| e.to_string())
+                            .expect("No error"),
+                    );
+                }
+
+                upload_frame(tile.texture.as_mut().unwrap(), &frame);
+            }
 
-                texture
-                    .with_lock(None, |buffer: &mut [u8], _pitch: usize| {
-                        match rx.try_recv() {
-                            Ok(frame) => {
-                                unsafe {
-                                    let frame_ptr = *frame.as_ptr();
-                                    ffmpeg_sys_next::av_image_copy_to_buffer(
-                                        buffer.as_mut_ptr(),
-                                        buffer_size,
-                                        frame_ptr.data.as_ptr() as *mut _,
-                                        frame_ptr.linesize.as_ptr() as *mut _,
-                                        frame.format().into(),
-                                        frame_ptr.width,
-                                        frame_ptr.height,
-                                        32,
-                                    );
-                                }
-                            }
-                            Err(_err) => {}
-                        }
-                    })
-                .expect("texture copy");
-
-                canvas.clear();
-                canvas.copy(&texture, None, None).expect("No error");
-                canvas.present();
+            if let Some(texture) = &tile.texture {
+                canvas.copy(texture, None, Some(rect)).expect("No error");
             }
         }
-        Err(_err) => {}
+        canvas.present();
     }
 }
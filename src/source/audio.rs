@@ -0,0 +1,154 @@
+use super::{AudioSource, Pacer};
+use anyhow::{anyhow, Context, Result};
+use ffmpeg_next::{
+    codec, decoder, device,
+    filter::{self, Graph},
+    format::{self, context::Input},
+    frame, media, Error, Packet,
+};
+use std::time::Duration;
+
+/// Decodes the audio stream of a capture device, file or URL
+pub struct AudioInput {
+    input: Input,
+    decoder: decoder::Audio,
+    stream_index: usize,
+    draining: bool,
+    looping: bool,
+    pacer: Option<Pacer>,
+}
+
+impl AudioInput {
+    /// Open an audio capture device through one of FFmpeg's input devices,
+    /// e.g. `pulse`, `alsa`, `dshow` or `avfoundation`
+    pub fn device(device_format: &str, device: &str) -> Result<Self> {
+        let format = device::input::audio()
+            .find(|format| format.name() == device_format)
+            .ok_or_else(|| anyhow!("Failed to find {} input device", device_format))?;
+
+        let input = format::open(device, &format)
+            .with_context(|| format!("Failed to open {} device {}", device_format, device))?
+            .input();
+
+        Self::new(input, false, false)
+    }
+
+    /// Open the audio track of a file or URL, read in real time
+    pub fn file(path: &str, looping: bool) -> Result<Self> {
+        let input = format::input(path).with_context(|| format!("Failed to open {}", path))?;
+
+        Self::new(input, looping, true)
+    }
+
+    fn new(input: Input, looping: bool, paced: bool) -> Result<Self> {
+        let stream = input
+            .streams()
+            .best(media::Type::Audio)
+            .ok_or_else(|| anyhow!("Input has no audio stream"))?;
+        let stream_index = stream.index();
+        let pacer = paced.then(|| {
+            let first_pts = match stream.start_time() {
+                ffmpeg_next::ffi::AV_NOPTS_VALUE => 0,
+                start_time => start_time,
+            };
+            Pacer::new(stream.time_base(), first_pts)
+        });
+        let decoder = codec::context::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .audio()?;
+
+        Ok(Self {
+            input,
+            decoder,
+            stream_index,
+            draining: false,
+            looping,
+            pacer,
+        })
+    }
+
+    fn read_packet(&mut self) -> Result<Option<Packet>> {
+        loop {
+            let mut packet = Packet::empty();
+            match packet.read(&mut self.input) {
+                Ok(_) if packet.stream() == self.stream_index => return Ok(Some(packet)),
+                Ok(_) => continue,
+                Err(Error::Eof) => return Ok(None),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn rewind(&mut self) -> Result<()> {
+        if !self.looping {
+            return Err(Error::Eof.into());
+        }
+
+        self.input.seek(0, ..)?;
+        self.decoder.flush();
+        self.draining = false;
+        if let Some(pacer) = &mut self.pacer {
+            pacer.rewind(Duration::ZERO);
+        }
+
+        Ok(())
+    }
+}
+
+impl AudioSource for AudioInput {
+    fn get_frame(&mut self) -> Result<frame::Audio> {
+        let mut frame = frame::Audio::empty();
+        loop {
+            if self.decoder.receive_frame(&mut frame).is_ok() {
+                if let Some(pacer) = &mut self.pacer {
+                    pacer.pace(frame.timestamp().or(frame.pts()));
+                }
+                return Ok(frame);
+            }
+
+            if self.draining {
+                self.rewind()?;
+                continue;
+            }
+
+            match self.read_packet()? {
+                Some(packet) => self.decoder.send_packet(&packet)?,
+                None => {
+                    self.decoder.send_eof()?;
+                    self.draining = true;
+                }
+            }
+        }
+    }
+}
+
+/// A sine tone that beeps once a second, the audio counterpart of testsrc
+pub struct Tone {
+    graph: Graph,
+}
+
+impl Tone {
+    pub fn new() -> Result<Self> {
+        let mut graph = filter::Graph::new();
+
+        let buffer_sink = filter::find("abuffersink")
+            .ok_or_else(|| anyhow!("Failed to find abuffersink filter"))?;
+
+        graph.add(&buffer_sink, "out", "")?;
+        graph
+            .input("out", 0)?
+            .parse("sine=frequency=1000:beep_factor=4:sample_rate=48000,arealtime")?;
+        graph.validate()?;
+
+        Ok(Self { graph })
+    }
+}
+
+impl AudioSource for Tone {
+    fn get_frame(&mut self) -> Result<frame::Audio> {
+        let mut frame = frame::Audio::empty();
+        self.graph.get("out").unwrap().sink().frame(&mut frame)?;
+
+        Ok(frame)
+    }
+}
@@ -10,14 +10,16 @@ pub struct DisplayDuplicator {
 }
 
 impl DisplayDuplicator {
-    pub fn new() -> Result<Self> {
+    pub fn new(framerate: u32) -> Result<Self> {
         let mut graph = filter::Graph::new();
 
         let buffer_sink = filter::find("buffersink")
             .ok_or_else(|| anyhow!("Failed to find buffersink filter"))?;
 
         graph.add(&buffer_sink, "out", "")?;
-        graph.input("out", 0)?.parse("ddagrab=0:framerate=60")?;
+        graph
+            .input("out", 0)?
+            .parse(&format!("ddagrab=0:framerate={}", framerate))?;
         graph.validate()?;
 
         Ok(Self { graph })
@@ -0,0 +1,259 @@
+use super::{Pacer, Source};
+use anyhow::{anyhow, bail, Context, Result};
+use ffmpeg_next::{
+    codec, decoder,
+    error::EAGAIN,
+    ffi::{
+        av_bsf_alloc, av_bsf_flush, av_bsf_free, av_bsf_get_by_name, av_bsf_init,
+        av_bsf_receive_packet, av_bsf_send_packet, avcodec_parameters_copy, AVBSFContext,
+    },
+    format::{self, context::Input},
+    frame, media,
+    packet::Mut,
+    Error, Packet, Rational,
+};
+use std::{ffi::CString, ptr, time::Duration};
+
+pub struct FileSource {
+    input: Input,
+    stream_index: usize,
+    parameters: codec::Parameters,
+    time_base: Rational,
+    frame_rate: Rational,
+    decoder: decoder::Video,
+    draining: bool,
+    annexb: Option<BitstreamFilter>,
+    looping: bool,
+    pacer: Pacer,
+}
+
+impl FileSource {
+    pub fn new(path: &str, looping: bool) -> Result<Self> {
+        let input = format::input(path).with_context(|| format!("Failed to open {}", path))?;
+
+        let stream = input
+            .streams()
+            .best(media::Type::Video)
+            .ok_or_else(|| anyhow!("{} has no video stream", path))?;
+        let stream_index = stream.index();
+        let parameters = stream.parameters();
+        let time_base = stream.time_base();
+        let frame_rate = match stream.avg_frame_rate() {
+            rate if rate.numerator() > 0 && rate.denominator() > 0 => rate,
+            _ => Rational::new(30, 1),
+        };
+        let first_pts = match stream.start_time() {
+            ffmpeg_next::ffi::AV_NOPTS_VALUE => 0,
+            start_time => start_time,
+        };
+        let decoder = codec::context::Context::from_parameters(parameters.clone())?
+            .decoder()
+            .video()?;
+
+        Ok(Self {
+            input,
+            stream_index,
+            parameters,
+            time_base,
+            frame_rate,
+            decoder,
+            draining: false,
+            annexb: None,
+            looping,
+            pacer: Pacer::new(time_base, first_pts),
+        })
+    }
+
+    /// Frame rate of the video stream rounded to whole frames per second
+    pub fn framerate(&self) -> u32 {
+        f64::from(self.frame_rate).round().max(1.0) as u32
+    }
+
+    /// Constrained Baseline H264 can be sent as is under the negotiated
+    /// 42e01f payload type, anything else is re-encoded
+    pub fn passthrough_compatible(&self) -> bool {
+        if self.parameters.id() != codec::Id::H264
+            || unsafe { (*self.parameters.as_ptr()).video_delay } != 0
+        {
+            return false;
+        }
+
+        match profile_level_id(&self.parameters) {
+            Some(profile) => is_constrained_baseline(profile),
+            None => false,
+        }
+    }
+
+    /// Pull the next Annex B H264 packet, paced to real time
+    pub fn get_packet(&mut self) -> Result<Packet> {
+        if self.annexb.is_none() {
+            self.annexb = Some(BitstreamFilter::new(
+                "h264_mp4toannexb",
+                &self.parameters,
+                self.time_base,
+            )?);
+        }
+
+        loop {
+            let Some(packet) = self.read_packet()? else {
+                self.rewind()?;
+                continue;
+            };
+
+            if let Some(packet) = self.annexb.as_mut().unwrap().filter(packet)? {
+                self.pacer.pace(packet.dts().or(packet.pts()));
+                return Ok(packet);
+            }
+        }
+    }
+
+    fn read_packet(&mut self) -> Result<Option<Packet>> {
+        loop {
+            let mut packet = Packet::empty();
+            match packet.read(&mut self.input) {
+                Ok(_) if packet.stream() == self.stream_index => return Ok(Some(packet)),
+                Ok(_) => continue,
+                Err(Error::Eof) => return Ok(None),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn rewind(&mut self) -> Result<()> {
+        if !self.looping {
+            return Err(Error::Eof.into());
+        }
+
+        self.input.seek(0, ..)?;
+        self.decoder.flush();
+        if let Some(annexb) = &mut self.annexb {
+            annexb.flush();
+        }
+        self.draining = false;
+        self.pacer
+            .rewind(Duration::from_secs_f64(f64::from(self.frame_rate.invert())));
+
+        Ok(())
+    }
+}
+
+impl Source for FileSource {
+    fn get_frame(&mut self) -> Result<frame::Video> {
+        let mut frame = frame::Video::empty();
+        loop {
+            if self.decoder.receive_frame(&mut frame).is_ok() {
+                self.pacer.pace(frame.timestamp().or(frame.pts()));
+                return Ok(frame);
+            }
+
+            if self.draining {
+                self.rewind()?;
+                continue;
+            }
+
+            match self.read_packet()? {
+                Some(packet) => self.decoder.send_packet(&packet)?,
+                None => {
+                    // Flush out frames still held by the decoder before looping
+                    self.decoder.send_eof()?;
+                    self.draining = true;
+                }
+            }
+        }
+    }
+}
+
+/// profile_idc, the constraint flags and level_idc of the SPS, from either an
+/// avcC record or Annex B extradata
+fn profile_level_id(parameters: &codec::Parameters) -> Option<[u8; 3]> {
+    let extradata = unsafe {
+        let parameters = &*parameters.as_ptr();
+        if parameters.extradata.is_null() || parameters.extradata_size <= 0 {
+            return None;
+        }
+        std::slice::from_raw_parts(parameters.extradata, parameters.extradata_size as usize)
+    };
+
+    if extradata.first() == Some(&1) {
+        return extradata.get(1..4)?.try_into().ok();
+    }
+
+    // Annex B, the SPS is the NAL unit of type 7 after a start code
+    extradata
+        .windows(3)
+        .enumerate()
+        .filter(|(_, window)| *window == [0, 0, 1])
+        .map(|(i, _)| &extradata[i + 3..])
+        .find(|nal| nal.first().is_some_and(|header| header & 0x1f == 7))
+        .and_then(|sps| sps.get(1..4)?.try_into().ok())
+}
+
+/// Whether a stream decodes as Constrained Baseline, per the profile_idc and
+/// constraint flag combinations of RFC 6184 section 8.1
+fn is_constrained_baseline([profile_idc, constraints, _]: [u8; 3]) -> bool {
+    match profile_idc {
+        0x42 => constraints & 0x40 != 0,
+        0x4d => constraints & 0x80 != 0,
+        0x58 => constraints & 0xc0 == 0xc0,
+        _ => false,
+    }
+}
+
+struct BitstreamFilter(*mut AVBSFContext);
+
+unsafe impl Send for BitstreamFilter {}
+
+impl BitstreamFilter {
+    fn new(name: &str, parameters: &codec::Parameters, time_base: Rational) -> Result<Self> {
+        let name = CString::new(name)?;
+        unsafe {
+            let filter = av_bsf_get_by_name(name.as_ptr());
+            if filter.is_null() {
+                bail!("Missing bitstream filter {:?}", name);
+            }
+
+            let mut context = ptr::null_mut();
+            check(av_bsf_alloc(filter, &mut context))?;
+            let bsf = Self(context);
+
+            check(avcodec_parameters_copy(
+                (*context).par_in,
+                parameters.as_ptr(),
+            ))?;
+            (*context).time_base_in = time_base.into();
+            check(av_bsf_init(context))?;
+
+            Ok(bsf)
+        }
+    }
+
+    fn filter(&mut self, mut packet: Packet) -> Result<Option<Packet>> {
+        let mut filtered = Packet::empty();
+        unsafe {
+            check(av_bsf_send_packet(self.0, packet.as_mut_ptr()))?;
+            match av_bsf_receive_packet(self.0, filtered.as_mut_ptr()) {
+                0 => Ok(Some(filtered)),
+                err if Error::from(err) == Error::Other { errno: EAGAIN } => Ok(None),
+                err => Err(Error::from(err).into()),
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        unsafe { av_bsf_flush(self.0) };
+    }
+}
+
+impl Drop for BitstreamFilter {
+    fn drop(&mut self) {
+        unsafe { av_bsf_free(&mut self.0) };
+    }
+}
+
+fn check(retval: i32) -> Result<()> {
+    if retval < 0 {
+        return Err(Error::from(retval).into());
+    }
+
+    Ok(())
+}
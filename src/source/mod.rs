@@ -1,9 +1,69 @@
 use anyhow::Result;
-use ffmpeg_next::frame::video::Video;
+use ffmpeg_next::{
+    frame::{audio::Audio, video::Video},
+    Rational,
+};
+use std::time::{Duration, Instant};
 
 #[cfg(target_os = "windows")]
 pub mod dxdup;
 
+#[cfg(target_os = "linux")]
+pub mod x11grab;
+
+pub mod audio;
+
+pub mod file;
+
+pub mod testsrc;
+
 pub trait Source {
     fn get_frame(&mut self) -> Result<Video>;
 }
+
+pub trait AudioSource {
+    fn get_frame(&mut self) -> Result<Audio>;
+}
+
+/// Sleeps until a timestamp is due so files are read at real-time speed,
+/// media time keeps increasing when the file loops
+pub struct Pacer {
+    time_base: Rational,
+    first_pts: i64,
+    start: Option<Instant>,
+    loop_offset: Duration,
+    media_time: Duration,
+}
+
+impl Pacer {
+    pub fn new(time_base: Rational, first_pts: i64) -> Self {
+        Self {
+            time_base,
+            first_pts,
+            start: None,
+            loop_offset: Duration::ZERO,
+            media_time: Duration::ZERO,
+        }
+    }
+
+    pub fn pace(&mut self, pts: Option<i64>) {
+        let Some(pts) = pts else {
+            return;
+        };
+
+        let offset = (pts - self.first_pts) as f64 * f64::from(self.time_base);
+        self.media_time = self.loop_offset + Duration::from_secs_f64(offset.max(0.0));
+
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let target = start + self.media_time;
+        let now = Instant::now();
+        if target > now {
+            std::thread::sleep(target - now);
+        }
+    }
+
+    /// Continue after the last paced timestamp plus `gap` once the input restarts
+    pub fn rewind(&mut self, gap: Duration) {
+        self.loop_offset = self.media_time + gap;
+    }
+}
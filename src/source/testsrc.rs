@@ -0,0 +1,52 @@
+use super::Source;
+use anyhow::{anyhow, Result};
+use ffmpeg_next::{
+    filter::{self, Graph},
+    frame,
+};
+
+pub enum Pattern {
+    TestSrc,
+    SmpteBars,
+}
+
+pub struct TestPattern {
+    graph: Graph,
+}
+
+impl TestPattern {
+    pub fn new(pattern: Pattern, video_size: &str, framerate: u32) -> Result<Self> {
+        let mut graph = filter::Graph::new();
+
+        let buffer_sink = filter::find("buffersink")
+            .ok_or_else(|| anyhow!("Failed to find buffersink filter"))?;
+
+        let generator = match pattern {
+            Pattern::TestSrc => "testsrc2",
+            Pattern::SmpteBars => "smptebars",
+        };
+
+        // Burn in the frame number and wall clock so received frames can be
+        // matched up against what was sent, realtime paces the generator
+        graph.add(&buffer_sink, "out", "")?;
+        graph.input("out", 0)?.parse(&format!(
+            "{}=size={}:rate={},\
+             drawtext=text='%{{n}} %{{localtime}}':fontsize=48:fontcolor=white:\
+             box=1:boxcolor=black@0.6:boxborderw=8:x=24:y=24,\
+             realtime,format=yuv420p",
+            generator, video_size, framerate
+        ))?;
+        graph.validate()?;
+
+        Ok(Self { graph })
+    }
+}
+
+impl Source for TestPattern {
+    fn get_frame(&mut self) -> Result<frame::Video> {
+        let mut frame = frame::Video::empty();
+        self.graph.get("out").unwrap().sink().frame(&mut frame)?;
+
+        Ok(frame)
+    }
+}
@@ -0,0 +1,67 @@
+use super::Source;
+use anyhow::{anyhow, Result};
+use ffmpeg_next::{
+    codec, decoder, device,
+    format::{self, context::Input},
+    frame, media, Dictionary, Packet,
+};
+
+pub struct X11Grab {
+    input: Input,
+    decoder: decoder::Video,
+    stream_index: usize,
+}
+
+impl X11Grab {
+    pub fn new(
+        display: &str,
+        offset: (u32, u32),
+        video_size: Option<&str>,
+        framerate: u32,
+    ) -> Result<Self> {
+        let format = device::input::video()
+            .find(|format| format.name() == "x11grab")
+            .ok_or_else(|| anyhow!("Failed to find x11grab input device"))?;
+
+        let mut options = Dictionary::new();
+        options.set("framerate", &framerate.to_string());
+        if let Some(video_size) = video_size {
+            options.set("video_size", video_size);
+        }
+
+        let url = format!("{}+{},{}", display, offset.0, offset.1);
+        let input = format::open_with(&url, &format, options)?.input();
+
+        let stream = input
+            .streams()
+            .best(media::Type::Video)
+            .ok_or_else(|| anyhow!("x11grab has no video stream"))?;
+        let stream_index = stream.index();
+        let decoder = codec::context::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .video()?;
+
+        Ok(Self {
+            input,
+            decoder,
+            stream_index,
+        })
+    }
+}
+
+impl Source for X11Grab {
+    fn get_frame(&mut self) -> Result<frame::Video> {
+        let mut frame = frame::Video::empty();
+        loop {
+            if self.decoder.receive_frame(&mut frame).is_ok() {
+                return Ok(frame);
+            }
+
+            let mut packet = Packet::empty();
+            packet.read(&mut self.input)?;
+            if packet.stream() == self.stream_index {
+                self.decoder.send_packet(&packet)?;
+            }
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! ICE-TCP transport. Every STUN, DTLS and SRTP packet is framed with a two
+//! byte length prefix as described in RFC 4571
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpSocket, TcpStream,
+    },
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+use tracing::{debug, info};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Active candidates don't accept connections, RFC 6544 has them use the discard port
+pub const ACTIVE_PORT: u16 = 9;
+
+enum TcpEvent {
+    Connected {
+        peer: SocketAddr,
+        writer: OwnedWriteHalf,
+    },
+    Disconnected {
+        peer: SocketAddr,
+    },
+    Frame {
+        peer: SocketAddr,
+        local: SocketAddr,
+        data: Vec<u8>,
+    },
+}
+
+/// Passive listeners plus the connections accepted on them or made actively
+pub struct TcpTransport {
+    events_tx: UnboundedSender<TcpEvent>,
+    events_rx: UnboundedReceiver<TcpEvent>,
+    writers: HashMap<SocketAddr, OwnedWriteHalf>,
+    connecting: HashSet<SocketAddr>,
+    passive: HashSet<SocketAddr>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl TcpTransport {
+    pub fn new() -> Self {
+        let (events_tx, events_rx) = unbounded_channel();
+        Self {
+            events_tx,
+            events_rx,
+            writers: HashMap::new(),
+            connecting: HashSet::new(),
+            passive: HashSet::new(),
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Accept connections on `ip`, returning the address of the passive candidate
+    pub async fn listen(&mut self, ip: IpAddr) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(SocketAddr::new(ip, 0)).await?;
+        let local = listener.local_addr()?;
+        let events_tx = self.events_tx.clone();
+        self.passive.insert(local);
+
+        self.tasks.push(tokio::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                info!("ICE-TCP connection from {} on {}", peer, local);
+                tokio::spawn(run_connection(stream, peer, local, events_tx.clone()));
+            }
+        }));
+
+        Ok(local)
+    }
+
+    /// Frame and send a packet, connecting to the peer first if `source` is an
+    /// active candidate. Packets sent while connecting are dropped, ICE
+    /// retransmits its checks
+    pub async fn send(
+        &mut self,
+        source: SocketAddr,
+        destination: SocketAddr,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let Some(writer) = self.writers.get_mut(&destination) else {
+            // Passive candidates only answer on connections made to them, and
+            // active ones on the other side have nothing to connect to
+            if !self.passive.contains(&source) && destination.port() != ACTIVE_PORT {
+                self.connect(source, destination);
+            }
+            return Ok(());
+        };
+
+        let mut frame = Vec::with_capacity(data.len() + 2);
+        frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        frame.extend_from_slice(data);
+
+        if let Err(e) = writer.write_all(&frame).await {
+            self.writers.remove(&destination);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// The next packet received on any connection, with its peer and local address
+    pub async fn recv(&mut self) -> (SocketAddr, SocketAddr, Vec<u8>) {
+        loop {
+            // events_tx is held by self, so the channel never closes
+            match self.events_rx.recv().await.expect("events_tx is held") {
+                TcpEvent::Connected { peer, writer } => {
+                    self.connecting.remove(&peer);
+                    self.writers.insert(peer, writer);
+                }
+                TcpEvent::Disconnected { peer } => {
+                    self.connecting.remove(&peer);
+                    self.writers.remove(&peer);
+                }
+                TcpEvent::Frame { peer, local, data } => return (peer, local, data),
+            }
+        }
+    }
+
+    fn connect(&mut self, source: SocketAddr, destination: SocketAddr) {
+        if !self.connecting.insert(destination) {
+            return;
+        }
+
+        let events_tx = self.events_tx.clone();
+        self.tasks.push(tokio::spawn(async move {
+            let connect = async {
+                let socket = match source {
+                    SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                    SocketAddr::V6(_) => TcpSocket::new_v6()?,
+                };
+                // Leave from the interface of the local candidate
+                socket.bind(SocketAddr::new(source.ip(), 0))?;
+                tokio::time::timeout(CONNECT_TIMEOUT, socket.connect(destination))
+                    .await
+                    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+            };
+
+            match connect.await {
+                Ok(stream) => {
+                    info!("ICE-TCP connected to {}", destination);
+                    // Received on the active candidate, not the ephemeral port
+                    // the connection happens to use
+                    run_connection(stream, destination, source, events_tx).await;
+                }
+                Err(e) => {
+                    debug!("ICE-TCP connect to {} failed: {:?}", destination, e);
+                    let _ = events_tx.send(TcpEvent::Disconnected { peer: destination });
+                }
+            }
+        }));
+    }
+}
+
+impl Drop for TcpTransport {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Hand the write half to the transport and forward frames until the
+/// connection or the transport goes away
+async fn run_connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    local: SocketAddr,
+    events_tx: UnboundedSender<TcpEvent>,
+) {
+    let _ = stream.set_nodelay(true);
+    let (mut reader, writer) = stream.into_split();
+    if events_tx
+        .send(TcpEvent::Connected { peer, writer })
+        .is_err()
+    {
+        return;
+    }
+
+    loop {
+        match read_frame(&mut reader).await {
+            Ok(data) => {
+                if events_tx
+                    .send(TcpEvent::Frame { peer, local, data })
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Err(e) => {
+                debug!("ICE-TCP connection to {} closed: {:?}", peer, e);
+                let _ = events_tx.send(TcpEvent::Disconnected { peer });
+                return;
+            }
+        }
+    }
+}
+
+async fn read_frame(reader: &mut OwnedReadHalf) -> io::Result<Vec<u8>> {
+    let mut length = [0; 2];
+    reader.read_exact(&mut length).await?;
+
+    let mut data = vec![0; u16::from_be_bytes(length) as usize];
+    reader.read_exact(&mut data).await?;
+
+    Ok(data)
+}
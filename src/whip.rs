@@ -1,49 +1,128 @@
-use crate::client::{Client, WebrtcEvent};
+use crate::client::{Client, WebrtcError, WebrtcEvent};
+use crate::ice::IceServer;
+use crate::player::{PlayerFrame, AUDIO_CHANNELS, AUDIO_SAMPLE_RATE};
 use crate::EncodedPacket;
 use bytes::Bytes;
 use ffmpeg_next;
 use futures::executor;
-use std::{sync::mpsc, time::Instant};
-use str0m::media::Direction as RtcDirection;
-use tokio::sync::mpsc::{error::TryRecvError, UnboundedReceiver};
-use tracing::{error, info};
+use std::{
+    future::Future,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+use str0m::{
+    format::Codec,
+    media::{Direction as RtcDirection, MediaData, MediaKind, MediaTime},
+};
+use tokio::{
+    sync::{
+        mpsc::{error::TryRecvError, unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::JoinHandle,
+};
+use tracing::{debug, error, info};
 
+/// Bounds for the video bitrate, which follows the bandwidth estimate. New
+/// targets in bits per second are sent on `tx`
+pub struct AdaptiveBitrate {
+    pub start: u64,
+    pub min: u64,
+    pub max: u64,
+    pub tx: UnboundedSender<u64>,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn publish(
     publish_url: &str,
     token: Option<String>,
-    mut packet_rx: UnboundedReceiver<EncodedPacket>,
+    audio: bool,
+    rids: &[&str],
+    ice_servers: &[IceServer],
+    packet_rx: UnboundedReceiver<EncodedPacket>,
+    keyframe_tx: UnboundedSender<()>,
+    bitrate: AdaptiveBitrate,
+    layers_tx: UnboundedSender<Vec<String>>,
 ) {
     info!(
         "creating client to push to {} with token: {:?}",
         publish_url, token
     );
 
-    let mut client = Client::new().await.unwrap();
+    let mut client = Client::with_bwe(bitrate.start).await.unwrap();
+    client.set_bitrate(bitrate.start, bitrate.max);
+    client.gather_candidates(ice_servers).await;
     client
-        .send_whip_request(&publish_url, &token, RtcDirection::SendOnly)
+        .send_whip_request(&publish_url, &token, RtcDirection::SendOnly, audio, rids)
         .await
         .expect("should connect");
+    // Only the layers the server takes are worth encoding
+    let _ = layers_tx.send(client.sent_rids().to_vec());
 
-    loop {
-        match client.recv().await {
+    // Nothing PATCHes a session we are the client of
+    let (_, patch_rx) = unbounded_channel();
+    send_loop(client, packet_rx, keyframe_tx, Some(bitrate), patch_rx).await;
+}
+
+/// Send encoded packets until the session ends, passing the peer's keyframe
+/// requests and, if adapting, bitrate changes on to whoever encodes them
+async fn send_loop(
+    mut client: Client,
+    mut packet_rx: UnboundedReceiver<EncodedPacket>,
+    keyframe_tx: UnboundedSender<()>,
+    bitrate: Option<AdaptiveBitrate>,
+    mut patch_rx: UnboundedReceiver<SessionPatch>,
+) {
+    'session: loop {
+        let event = tokio::select! {
+            event = client.recv() => event,
+            Some(patch) = patch_rx.recv() => {
+                let _ = patch.reply.send(client.accept_ice_fragment(&patch.fragment));
+                continue;
+            }
+        };
+
+        match event {
             Ok(event) => match event {
                 WebrtcEvent::Disconnected => {
                     info!("disconnected");
-                    break;
+                    // Only sessions we POSTed ourselves can be restarted
+                    if let Err(err) = client.reconnect().await {
+                        info!("Ending session, ICE restart not possible: {:?}", err);
+                        break;
+                    }
                 }
-                WebrtcEvent::Media(_) => {
-                    panic!("Publisher incorrectly has incoming media");
+                WebrtcEvent::Media(media) => {
+                    // Nothing is offered to receive, a peer sending anyway is ignored
+                    debug!("Ignoring incoming media on {:?}", media.mid);
+                }
+                WebrtcEvent::KeyframeRequest => {
+                    let _ = keyframe_tx.send(());
+                }
+                WebrtcEvent::BitrateEstimate(estimate) => {
+                    if let Some(bitrate) = &bitrate {
+                        let target = estimate.clamp(bitrate.min, bitrate.max);
+                        client.set_bitrate(target, bitrate.max);
+                        let _ = bitrate.tx.send(target);
+                    }
                 }
                 WebrtcEvent::Continue => loop {
                     let packet = packet_rx.try_recv();
                     match packet {
-                        Err(TryRecvError::Empty | TryRecvError::Disconnected) => break,
+                        Err(TryRecvError::Empty) => break,
+                        // The capture side has finished
+                        Err(TryRecvError::Disconnected) => break 'session,
                         Ok(packet) => {
-                            let pts = Instant::now() - packet.1;
-                            if let Some(data) = packet.0.data() {
-                                client
-                                    .send_video(Bytes::copy_from_slice(data), pts)
-                                    .unwrap();
+                            if let Some(data) = packet.packet.data() {
+                                let data = Bytes::copy_from_slice(data);
+                                let sent = match packet.kind {
+                                    MediaKind::Audio => client.send_audio(data, packet.pts),
+                                    _ => client.send_video(data, packet.pts, packet.rid),
+                                };
+                                if let Err(err) = sent {
+                                    error!("error sending media: {:?}", err);
+                                    break 'session;
+                                }
                             }
                         }
                     }
@@ -55,33 +134,219 @@ pub async fn publish(
             }
         }
     }
+
+    if let Err(err) = client.close().await {
+        error!("error closing session: {:?}", err);
+    }
+}
+
+fn create_opus_decoder() -> ffmpeg_next::decoder::Audio {
+    let codec =
+        ffmpeg_next::decoder::find(ffmpeg_next::codec::Id::OPUS).expect("Opus Decoder Available");
+    let mut context = ffmpeg_next::codec::context::Context::new_with_codec(codec);
+    // WebRTC carries no Opus extradata, tell the decoder what to expect
+    unsafe {
+        let context = &mut *context.as_mut_ptr();
+        context.sample_rate = AUDIO_SAMPLE_RATE as i32;
+        ffmpeg_next::ffi::av_channel_layout_default(&mut context.ch_layout, AUDIO_CHANNELS as i32);
+    }
+    context.decoder().audio().expect("Decoder init correctly")
+}
+
+/// A trickle ICE or ICE restart fragment PATCHed to a server session
+pub struct SessionPatch {
+    fragment: String,
+    reply: oneshot::Sender<Result<Option<String>, WebrtcError>>,
+}
+
+/// A session answered by subscribe_as_server
+pub struct Session {
+    task: JoinHandle<()>,
+    patch_tx: UnboundedSender<SessionPatch>,
+    pub etag: String,
+    /// Bearer token the session was created with, PATCH and DELETE must carry it too
+    pub token: Option<String>,
+}
+
+impl Session {
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    pub fn close(&self) {
+        self.task.abort();
+    }
+
+    /// Hand a PATCH body to the session, resolves to our own fragment if it
+    /// restarted ICE. The session isn't borrowed while waiting on the reply
+    pub fn patch(
+        &self,
+        fragment: String,
+    ) -> impl Future<Output = Result<Option<String>, WebrtcError>> {
+        let (reply, response) = oneshot::channel();
+        let sent = self.patch_tx.send(SessionPatch { fragment, reply });
+
+        async move {
+            sent.map_err(|_| WebrtcError::SendError("session has ended".to_string()))?;
+            response
+                .await
+                .map_err(|_| WebrtcError::SendError("session has ended".to_string()))?
+        }
+    }
+}
+
+/// Turns the RTP timestamps of a stream's tracks into capture times on one
+/// clock. Once the sender's RTCP Sender Reports are in, audio and video line up
+/// through the NTP time they share. Before that each track counts from the
+/// arrival of its first packet
+#[derive(Default)]
+struct MediaClock {
+    audio: Option<(Instant, MediaTime)>,
+    video: Option<(Instant, MediaTime)>,
+}
+
+impl MediaClock {
+    fn captured(&mut self, media: &MediaData, kind: MediaKind) -> Instant {
+        let (at, reference) = match media.last_sender_info {
+            Some(info) => (info.ntp_time, info.rtp_time),
+            None => {
+                let first = match kind {
+                    MediaKind::Audio => &mut self.audio,
+                    MediaKind::Video => &mut self.video,
+                };
+                *first.get_or_insert((media.network_time, media.time))
+            }
+        };
+
+        let offset = media.time.as_seconds() - reference.as_seconds();
+        let captured = if offset >= 0.0 {
+            at.checked_add(Duration::from_secs_f64(offset))
+        } else {
+            at.checked_sub(Duration::from_secs_f64(-offset))
+        };
+        captured.unwrap_or(at)
+    }
 }
 
-pub async fn decode_recv_loop(mut client: Client, tx: mpsc::Sender<ffmpeg_next::frame::Video>) {
+/// Carries a capture time through the decoder as a pts in microseconds
+fn to_pts(epoch: Instant, at: Instant) -> i64 {
+    match at.checked_duration_since(epoch) {
+        Some(after) => after.as_micros() as i64,
+        None => -(epoch.duration_since(at).as_micros() as i64),
+    }
+}
+
+fn from_pts(epoch: Instant, pts: i64) -> Instant {
+    let offset = Duration::from_micros(pts.unsigned_abs());
+    if pts >= 0 {
+        epoch + offset
+    } else {
+        epoch.checked_sub(offset).unwrap_or(epoch)
+    }
+}
+
+pub fn new_etag() -> String {
+    format!("\"{:016x}\"", rand::random::<u64>())
+}
+
+pub async fn decode_recv_loop(
+    mut client: Client,
+    tx: mpsc::Sender<PlayerFrame>,
+    mut patch_rx: UnboundedReceiver<SessionPatch>,
+) {
     let codec = ffmpeg_next::decoder::find_by_name("h264").expect("H264 Decoder Available");
     let context = ffmpeg_next::codec::context::Context::new_with_codec(codec);
     let mut decoder = context.decoder().video().expect("Decoder init correctly");
+    let mut audio_decoder = create_opus_decoder();
+    let mut resampler: Option<ffmpeg_next::software::resampling::Context> = None;
+    let mut clock = MediaClock::default();
+    let epoch = Instant::now();
+    // Set after packet loss or a decode error, until a keyframe comes in.
+    // Anything in between references pictures the decoder doesn't have
+    let mut broken = false;
 
     loop {
-        match client.recv().await {
+        let event = tokio::select! {
+            event = client.recv() => event,
+            Some(patch) = patch_rx.recv() => {
+                let _ = patch.reply.send(client.accept_ice_fragment(&patch.fragment));
+                continue;
+            }
+        };
+
+        match event {
             Ok(event) => match event {
                 WebrtcEvent::Disconnected => {
                     info!("disconnected");
-                    break;
+                    // Only sessions we POSTed ourselves can be restarted
+                    if let Err(err) = client.reconnect().await {
+                        info!("Ending session, ICE restart not possible: {:?}", err);
+                        break;
+                    }
+                }
+                WebrtcEvent::Media(media) if media.params.spec().codec == Codec::Opus => {
+                    let captured = clock.captured(&media, MediaKind::Audio);
+                    if audio_decoder
+                        .send_packet(&ffmpeg_next::Packet::borrow(&media.data))
+                        .is_err()
+                    {
+                        continue;
+                    }
+
+                    let mut frame = ffmpeg_next::frame::Audio::empty();
+                    while audio_decoder.receive_frame(&mut frame).is_ok() {
+                        // Convert to the packed stereo the player's audio queue takes
+                        let resampler = resampler.get_or_insert_with(|| {
+                            frame
+                                .resampler(
+                                    ffmpeg_next::format::Sample::F32(
+                                        ffmpeg_next::format::sample::Type::Packed,
+                                    ),
+                                    ffmpeg_next::ChannelLayout::STEREO,
+                                    AUDIO_SAMPLE_RATE,
+                                )
+                                .expect("Resampler init correctly")
+                        });
+
+                        let mut resampled = ffmpeg_next::frame::Audio::empty();
+                        if resampler.run(&frame, &mut resampled).is_ok() {
+                            tx.send(PlayerFrame::Audio(resampled, captured))
+                                .expect("pushed");
+                        }
+                    }
                 }
                 WebrtcEvent::Media(media) => {
-                    // Decoder failures may happen, ignore them
-                    match decoder.send_packet(&ffmpeg_next::Packet::borrow(&media.data)) {
-                        Err(_) => continue,
-                        Ok(_) => {}
-                    };
+                    // A frame that still has holes after NACKs is lost
+                    if !media.contiguous {
+                        broken = true;
+                    }
+                    if broken {
+                        if !is_h264_keyframe(&media.data) {
+                            client.request_keyframe();
+                            continue;
+                        }
+                        broken = false;
+                    }
+
+                    let captured = clock.captured(&media, MediaKind::Video);
+                    let mut packet = ffmpeg_next::Packet::copy(&media.data);
+                    packet.set_pts(Some(to_pts(epoch, captured)));
+                    if decoder.send_packet(&packet).is_err() {
+                        broken = true;
+                        client.request_keyframe();
+                        continue;
+                    }
 
                     let mut frame = ffmpeg_next::frame::Video::empty();
                     while decoder.receive_frame(&mut frame).is_ok() {
-                        tx.send(frame).expect("pushed");
+                        let captured = frame.pts().map_or(captured, |pts| from_pts(epoch, pts));
+                        tx.send(PlayerFrame::Video(frame, captured))
+                            .expect("pushed");
                         frame = ffmpeg_next::frame::Video::empty();
                     }
                 }
+                // Nothing is sent back to a publisher
+                WebrtcEvent::KeyframeRequest | WebrtcEvent::BitrateEstimate(_) => {}
                 WebrtcEvent::Continue => {
                     info!("Continue");
                 }
@@ -92,30 +357,179 @@ pub async fn decode_recv_loop(mut client: Client, tx: mpsc::Sender<ffmpeg_next::
             }
         }
     }
+
+    if let Err(err) = client.close().await {
+        error!("error closing session: {:?}", err);
+    }
+}
+
+/// Pass received media on still encoded, for relaying to other sessions.
+/// Keyframe requests from those sessions are passed back to the sender
+async fn forward_recv_loop(
+    mut client: Client,
+    packet_tx: UnboundedSender<EncodedPacket>,
+    mut keyframe_rx: UnboundedReceiver<()>,
+    mut patch_rx: UnboundedReceiver<SessionPatch>,
+) {
+    loop {
+        let event = tokio::select! {
+            event = client.recv() => event,
+            Some(patch) = patch_rx.recv() => {
+                let _ = patch.reply.send(client.accept_ice_fragment(&patch.fragment));
+                continue;
+            }
+            Some(()) = keyframe_rx.recv() => {
+                client.request_keyframe();
+                continue;
+            }
+        };
+
+        match event {
+            Ok(WebrtcEvent::Disconnected) => {
+                info!("disconnected");
+                break;
+            }
+            Ok(WebrtcEvent::Media(media)) => {
+                let kind = match media.params.spec().codec {
+                    Codec::Opus => MediaKind::Audio,
+                    _ => MediaKind::Video,
+                };
+                let mut packet = ffmpeg_next::Packet::copy(&media.data);
+                if kind == MediaKind::Video && is_h264_keyframe(&media.data) {
+                    packet.set_flags(ffmpeg_next::packet::Flags::KEY);
+                }
+
+                // The publisher's own timestamps, re-stamping on arrival
+                // would add the network's jitter to them
+                let packet = EncodedPacket {
+                    kind,
+                    packet,
+                    pts: media_time_pts(media.time),
+                    rid: None,
+                };
+                if packet_tx.send(packet).is_err() {
+                    break;
+                }
+            }
+            Ok(
+                WebrtcEvent::KeyframeRequest
+                | WebrtcEvent::BitrateEstimate(_)
+                | WebrtcEvent::Continue,
+            ) => {}
+            Err(err) => {
+                error!("error: {:?}", err);
+                break;
+            }
+        }
+    }
+
+    if let Err(err) = client.close().await {
+        error!("error closing session: {:?}", err);
+    }
+}
+
+/// A media time as a pts that turns back into the same media time when it is
+/// rebased to the clock rate for sending. Rounding down would lose a tick
+fn media_time_pts(time: MediaTime) -> Duration {
+    let micros = time.numer().max(0) as u64 * 1_000_000;
+    Duration::from_micros(micros.div_ceil(time.denom() as u64))
+}
+
+/// Whether an Annex B access unit holds an IDR slice or parameter sets
+fn is_h264_keyframe(data: &[u8]) -> bool {
+    data.windows(4)
+        .any(|window| window[..3] == [0, 0, 1] && matches!(window[3] & 0x1f, 5 | 7))
 }
 
 pub async fn subscribe_as_client(
-    tx: mpsc::Sender<ffmpeg_next::frame::Video>,
+    tx: mpsc::Sender<PlayerFrame>,
     publish_url: &str,
     token: Option<String>,
+    ice_servers: &[IceServer],
 ) {
     let mut client = Client::new().await.unwrap();
+    client.gather_candidates(ice_servers).await;
     client
-        .send_whip_request(&publish_url, &token, RtcDirection::RecvOnly)
+        .send_whip_request(&publish_url, &token, RtcDirection::RecvOnly, true, &[])
         .await
         .expect("should connect");
 
+    // Nothing PATCHes a session we are the client of
+    let (_, patch_rx) = unbounded_channel();
     tokio::task::spawn(async move {
-        decode_recv_loop(client, tx).await;
+        decode_recv_loop(client, tx, patch_rx).await;
     });
 }
 
-pub fn subscribe_as_server(tx: mpsc::Sender<ffmpeg_next::frame::Video>, offer: String) -> String {
-    let mut client = executor::block_on(Client::new()).expect("Ok");
-    let answer = client.accept_whip_request(offer).expect("Ok");
-    tokio::task::spawn(async move {
-        decode_recv_loop(client, tx).await;
+/// Answer a WHEP offer and send it the packets from `packet_rx`, the viewer's
+/// keyframe requests come out of `keyframe_tx`
+pub fn serve_as_server(
+    packet_rx: UnboundedReceiver<EncodedPacket>,
+    keyframe_tx: UnboundedSender<()>,
+    offer: String,
+) -> Result<(String, Session), WebrtcError> {
+    let mut client = executor::block_on(Client::new())?;
+    let answer = client.accept_whip_request(offer)?;
+    let (patch_tx, patch_rx) = unbounded_channel();
+    let task = tokio::task::spawn(async move {
+        send_loop(client, packet_rx, keyframe_tx, None, patch_rx).await;
+    });
+
+    Ok((
+        answer,
+        Session {
+            task,
+            patch_tx,
+            etag: new_etag(),
+            token: None,
+        },
+    ))
+}
+
+/// Answer a WHIP offer and pass its media on to `packet_tx` without decoding,
+/// asking the publisher for a keyframe on each message from `keyframe_rx`
+pub fn relay_as_server(
+    packet_tx: UnboundedSender<EncodedPacket>,
+    keyframe_rx: UnboundedReceiver<()>,
+    offer: String,
+) -> Result<(String, Session), WebrtcError> {
+    let mut client = executor::block_on(Client::new())?;
+    let answer = client.accept_whip_request(offer)?;
+    let (patch_tx, patch_rx) = unbounded_channel();
+    let task = tokio::task::spawn(async move {
+        forward_recv_loop(client, packet_tx, keyframe_rx, patch_rx).await;
+    });
+
+    Ok((
+        answer,
+        Session {
+            task,
+            patch_tx,
+            etag: new_etag(),
+            token: None,
+        },
+    ))
+}
+
+/// Answer a WHIP offer and play it
+pub fn subscribe_as_server(
+    tx: mpsc::Sender<PlayerFrame>,
+    offer: String,
+) -> Result<(String, Session), WebrtcError> {
+    let mut client = executor::block_on(Client::new())?;
+    let answer = client.accept_whip_request(offer)?;
+    let (patch_tx, patch_rx) = unbounded_channel();
+    let task = tokio::task::spawn(async move {
+        decode_recv_loop(client, tx, patch_rx).await;
     });
 
-    answer
+    Ok((
+        answer,
+        Session {
+            task,
+            patch_tx,
+            etag: new_etag(),
+            token: None,
+        },
+    ))
 }